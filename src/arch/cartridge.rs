@@ -1,31 +1,167 @@
+use std::fmt::{Display, Formatter};
 use crate::arch::BusAccessable;
+use crate::arch::mapper::detect::{detect, Detection, MapperKind};
+use crate::arch::mapper::{Mapper, Segment};
+use crate::arch::mapper::standard::Standard;
+use crate::arch::mapper::thumb::ArmError;
+use crate::arch::state::{Savestate, StateError, StateReader, StateWriter};
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    /// No supported bankswitching scheme uses ROMs of this size.
+    UnsupportedSize(usize),
+    /// The ROM's size doesn't fit the requested scheme.
+    SizeMismatch(MapperKind, usize),
+    /// The requested scheme isn't known.
+    UnknownMapper(String),
+}
+impl Display for CartridgeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CartridgeError::UnsupportedSize(len) => write!(f, "no supported bankswitching scheme uses {} byte ROMs", len),
+            CartridgeError::SizeMismatch(kind, len) => write!(f, "a {} byte ROM can't use the {} bankswitching scheme", len, kind),
+            CartridgeError::UnknownMapper(name) => write!(f, "unknown bankswitching scheme: {}", name),
+        }
+    }
+}
+impl std::error::Error for CartridgeError {}
 
 #[derive(Clone, Debug)]
 pub struct Cartridge {
-    rom: Vec<u8>,
+    mapper: Box<dyn Mapper>,
+    /// MD5 of the loaded ROM image, used to make sure savestates are loaded into the same game.
+    rom_md5: [u8; 16],
 }
 impl Default for Cartridge {
     fn default() -> Self {
         Self {
-            rom: vec![0; 1024 * 4]
+            mapper: Box::new(Standard::new(&[0; 1024 * 4])),
+            rom_md5: [0; 16],
         }
     }
 }
 
 impl BusAccessable for Cartridge {
     fn write(&mut self, addr: u16, data: u8) {
-        todo!()
+        self.mapper.write(addr & 0x1FFF, data);
     }
     fn read(&mut self, addr: u16) -> u8 {
-        self.rom[(addr & 0x0FFF) as usize]
+        self.mapper.read(addr & 0x1FFF)
     }
 }
 
 impl Cartridge {
-    pub fn set_rom(&mut self, rom: &Vec<u8>) {
-        self.rom = rom.to_owned();
-        if self.rom.len() < 4096 {
-            self.rom.resize(4096, 0);
+    /// Loads a ROM image, detecting which mapper it uses.
+    pub fn set_rom(&mut self, rom: &[u8]) -> Result<Detection, CartridgeError> {
+        let detection = detect(rom)?;
+        self.mapper = detection.kind.create(rom)?;
+        self.rom_md5 = md5::compute(rom).0;
+        
+        Ok(detection)
+    }
+    
+    /// Loads a ROM image with a specific mapper, skipping detection.
+    pub fn set_rom_as(&mut self, rom: &[u8], kind: MapperKind) -> Result<(), CartridgeError> {
+        self.mapper = kind.create(rom)?;
+        self.rom_md5 = md5::compute(rom).0;
+        
+        Ok(())
+    }
+    
+    /// MD5 of the loaded ROM image, as a lowercase hex string.
+    pub fn rom_md5(&self) -> String {
+        format!("{:x}", md5::Digest(self.rom_md5))
+    }
+    
+    /// Lets the mapper see an access anywhere on the bus. `addr` must already be masked to 13 bits.
+    pub fn snoop(&mut self, addr: u16, data: u8, write: bool) {
+        self.mapper.snoop(addr, data, write);
+    }
+    
+    /// Clocks any hardware on the cartridge. Should be called once per CPU cycle.
+    pub fn cycle(&mut self) {
+        self.mapper.cycle();
+    }
+    
+    /// Takes the next write into RIOT RAM that the cartridge wants to make.
+    pub fn take_ram_poke(&mut self) -> Option<(u8, u8)> {
+        self.mapper.take_ram_poke()
+    }
+    
+    /// Takes the error that just halted the cartridge's coprocessor, if there was one.
+    pub fn take_error(&mut self) -> Option<ArmError> {
+        self.mapper.take_error()
+    }
+    
+    /// Whether the cartridge is currently holding the CPU.
+    pub fn stalling(&self) -> bool {
+        self.mapper.stalling()
+    }
+    
+    /// Replaces the mapper, keeping the MD5 of the ROM image that was loaded last.
+    pub fn set_mapper(&mut self, mapper: Box<dyn Mapper>) {
+        self.mapper = mapper;
+    }
+    
+    /// Banks currently mapped into cartridge space.
+    pub fn segments(&self) -> Vec<Segment> {
+        self.mapper.segments()
+    }
+    
+    /// Describes which banks are mapped where, like `$F000-$FFFF: ROM bank 3`.
+    pub fn bank_summary(&self) -> String {
+        self.segments().iter().map(|segment| segment.to_string()).collect::<Vec<_>>().join(", ")
+    }
+    
+    /// RAM on the cartridge, for cartridges that have any.
+    pub fn ram(&self) -> Option<&[u8]> {
+        self.mapper.ram()
+    }
+    
+    /// Replaces the contents of the cartridge RAM, like when restoring it from a previous session.
+    pub fn load_ram(&mut self, data: &[u8]) -> Result<(), StateError> {
+        match self.mapper.ram_mut() {
+            Some(ram) if ram.len() == data.len() => {
+                ram.copy_from_slice(data);
+                Ok(())
+            },
+            _ => Err(StateError::Mismatch("cartridge RAM")),
+        }
+    }
+}
+
+impl Savestate for Cartridge {
+    fn save(&self, w: &mut StateWriter) {
+        self.rom_md5.save(w);
+        self.mapper.save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let mut rom_md5 = [0u8; 16];
+        rom_md5.load(r)?;
+        if rom_md5 != self.rom_md5 {
+            return Err(StateError::Mismatch("ROM"));
         }
+        
+        self.mapper.load(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::mapper::atari::Scheme;
+    
+    #[test]
+    fn load_ram() {
+        let mut cart = Cartridge::default();
+        assert!(cart.ram().is_none());
+        assert!(cart.load_ram(&[0; 128]).is_err());
+        
+        cart.set_rom_as(&[0; 8192], MapperKind::Atari(Scheme::F8, true)).unwrap();
+        let saved: Vec<u8> = (0..128).collect();
+        cart.load_ram(&saved).unwrap();
+        assert_eq!(cart.ram(), Some(&saved[..]));
+        assert_eq!(cart.read(0x1085), 5);
+        assert!(cart.load_ram(&saved[..64]).is_err());
     }
 }
\ No newline at end of file
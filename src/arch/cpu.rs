@@ -4,7 +4,9 @@
 use std::fmt::{Debug, Formatter};
 use std::num::Wrapping;
 use crate::arch::BusAccessable;
-use crate::{Bus, InfCell};
+use crate::arch::state::{Savestate, StateError, StateReader, StateWriter};
+use crate::arch::Bus;
+use crate::util::InfCell;
 use bitflags::bitflags;
 
 
@@ -67,6 +69,8 @@ use AddrMode::*;
 #[derive(Copy, Clone)]
 pub struct InstructionProcedure {
     pub done: bool,
+    /// Opcode this procedure was decoded from, so it can be decoded again after loading a state.
+    opcode: u8,
     func: fn(&mut Self, &mut Cpu, &mut Bus),
     mode: AddrMode,
     cycle: u8,
@@ -86,6 +90,7 @@ impl InstructionProcedure {
     pub fn new(step_func: fn(&mut InstructionProcedure, &mut Cpu, &mut Bus), addr_mode: AddrMode) -> Self {
         Self {
             done: false,
+            opcode: 0,
             func: step_func,
             mode: addr_mode,
             cycle: 1,
@@ -95,74 +100,10 @@ impl InstructionProcedure {
         }
     }
     
-    pub fn step(&mut self, cpu: &mut Cpu, bus: &mut Bus) {
-        (self.func)(self, cpu, bus);
-        self.cycle += 1;
-    }
-}
-
-
-#[derive(Clone, Debug)]
-pub struct Cpu {
-    pub pc: u16,
-    pub sp: Wrapping<u8>,
-    pub status: StatusReg,
-    pub acc: u8,
-    pub x: u8,
-    pub y: u8,
-    pub rdy: bool,
-    stack: [u8; 256],
-    prefetch: Option<u8>,
-    fetch_needed: bool,
-    cycles_to_wait: u8,
-    procedure: Option<InstructionProcedure>,
-    counter: usize,
-}
-impl Default for Cpu {
-    fn default() -> Self {
-        Self {
-            pc: 0,
-            sp: Wrapping(0), // actually this is potentialy random at power-on // software typically initializes this to 0xFF
-            status: StatusReg::default(),
-            acc: 0,
-            x: 0,
-            y: 0,
-            rdy: true,
-            stack: [0u8; 256],
-            prefetch: None,
-            fetch_needed: false, // used for debugging
-            cycles_to_wait: 0,
-            procedure: None,
-            counter: 1,
-        }
-    }
-}
-
-impl Cpu {
-    pub fn init_pc(&mut self, bus: &mut Bus) {
-        self.pc = ((bus.cart.read(0xFFFD) as u16) << 8) | (bus.cart.read(0xFFFC) as u16);
-    }
-    
-    pub fn cycle(&mut self, bus_cell: &InfCell<Bus>) {
-        if !self.rdy {
-            return;
-        }
-        
-        let bus = bus_cell.get_mut();
-        //let bus_ref = bus_cell.get_mut();
-        
-        if self.procedure.is_none() {
-            if self.prefetch.is_none() { // if next instruction wasn't prefetched at end of previous, we must fetch now (this is considered the first cycle of procedure)
-                self.prefetch = Some(self.fetch(bus));
-                
-                //println!("Fetched! PC: {:04X}, Op: {:02X}, Status: {}, ACC: {:02X}, X: {:02X}, Y: {:02X}, SP: {:02X}", self.pc - 1, self.prefetch.unwrap(), self.status, self.acc, self.x, self.y, self.sp);
-                self.fetch_needed = true;
-            }
-            
-            let opcode = self.prefetch.unwrap();
-            self.prefetch = None;
-            
-            self.procedure = Some(match opcode {
+    /// Decodes an opcode into the procedure that runs it, or None if the opcode is invalid or
+    /// not implemented.
+    pub fn decode(opcode: u8) -> Option<Self> {
+        let mut procedure = match opcode {
             
             0x00 => InstructionProcedure::new(brk, Auto),
             0x01 => InstructionProcedure::new(ora, IndirectX),
@@ -424,8 +365,153 @@ impl Cpu {
             0xFE => InstructionProcedure::new(inc, AbsoluteX),
             0xFF => InstructionProcedure::new(isb, AbsoluteX),
             
-            _ => panic!("Attempt to run invalid/unimplemented opcode! PC: {:#06X}, Op: {:#06X}", self.pc, opcode)
-        }); // decode opcode into an instruction procedure (this doesn't consume cycles)
+            _ => return None,
+        };
+        procedure.opcode = opcode;
+        
+        Some(procedure)
+    }
+    
+    pub fn step(&mut self, cpu: &mut Cpu, bus: &mut Bus) {
+        (self.func)(self, cpu, bus);
+        self.cycle += 1;
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct Cpu {
+    pub pc: u16,
+    pub sp: Wrapping<u8>,
+    pub status: StatusReg,
+    pub acc: u8,
+    pub x: u8,
+    pub y: u8,
+    pub rdy: bool,
+    prefetch: Option<u8>,
+    fetch_needed: bool,
+    cycles_to_wait: u8,
+    procedure: Option<InstructionProcedure>,
+    counter: usize,
+}
+impl Default for Cpu {
+    fn default() -> Self {
+        Self {
+            pc: 0,
+            sp: Wrapping(0), // random at power-on (see `power_on`), software typically initializes this to 0xFF
+            status: StatusReg::default(),
+            acc: 0,
+            x: 0,
+            y: 0,
+            rdy: true,
+            prefetch: None,
+            fetch_needed: false, // used for debugging
+            cycles_to_wait: 0,
+            procedure: None,
+            counter: 1,
+        }
+    }
+}
+
+/// The in-flight procedure is saved as its opcode and microstate, and decoded again on load.
+impl Savestate for Cpu {
+    fn save(&self, w: &mut StateWriter) {
+        self.pc.save(w);
+        self.sp.save(w);
+        self.status.bits().save(w);
+        self.acc.save(w);
+        self.x.save(w);
+        self.y.save(w);
+        self.rdy.save(w);
+        self.prefetch.save(w);
+        self.fetch_needed.save(w);
+        self.cycles_to_wait.save(w);
+        self.counter.save(w);
+        
+        self.procedure.is_some().save(w);
+        if let Some(procedure) = &self.procedure {
+            procedure.opcode.save(w);
+            procedure.done.save(w);
+            procedure.cycle.save(w);
+            procedure.tmp0.save(w);
+            procedure.tmp1.save(w);
+            procedure.tmp_addr.save(w);
+        }
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.pc.load(r)?;
+        self.sp.load(r)?;
+        let mut status = 0u8;
+        status.load(r)?;
+        self.status = StatusReg::from_bits_truncate(status);
+        self.acc.load(r)?;
+        self.x.load(r)?;
+        self.y.load(r)?;
+        self.rdy.load(r)?;
+        self.prefetch.load(r)?;
+        self.fetch_needed.load(r)?;
+        self.cycles_to_wait.load(r)?;
+        self.counter.load(r)?;
+        
+        let mut in_flight = false;
+        in_flight.load(r)?;
+        self.procedure = None;
+        if in_flight {
+            let mut opcode = 0u8;
+            opcode.load(r)?;
+            
+            let mut procedure = InstructionProcedure::decode(opcode).ok_or(StateError::Invalid("opcode"))?;
+            procedure.done.load(r)?;
+            procedure.cycle.load(r)?;
+            procedure.tmp0.load(r)?;
+            procedure.tmp1.load(r)?;
+            procedure.tmp_addr.load(r)?;
+            self.procedure = Some(procedure);
+        }
+        
+        Ok(())
+    }
+}
+
+impl Cpu {
+    pub fn init_pc(&mut self, bus: &mut Bus) {
+        self.pc = ((bus.cart.read(0xFFFD) as u16) << 8) | (bus.cart.read(0xFFFC) as u16);
+    }
+    
+    /// Sets the registers that are undefined at power-on.
+    pub fn power_on(&mut self, random: &mut impl FnMut() -> u8) {
+        self.acc = random();
+        self.x = random();
+        self.y = random();
+        self.sp = Wrapping(random());
+        self.status = StatusReg::from_bits_truncate(random()) | StatusReg::default();
+    }
+    
+    /// Whether the CPU is between instructions, with nothing in flight.
+    pub fn between_instructions(&self) -> bool {
+        self.procedure.is_none()
+    }
+    
+    pub fn cycle(&mut self, bus_cell: &InfCell<Bus>) {
+        if !self.rdy {
+            return;
+        }
+        
+        let bus = bus_cell.get_mut();
+        //let bus_ref = bus_cell.get_mut();
+        
+        if self.procedure.is_none() {
+            if self.prefetch.is_none() { // if next instruction wasn't prefetched at end of previous, we must fetch now (this is considered the first cycle of procedure)
+                self.prefetch = Some(self.fetch(bus));
+                
+                //println!("Fetched! PC: {:04X}, Op: {:02X}, Status: {}, ACC: {:02X}, X: {:02X}, Y: {:02X}, SP: {:02X}", self.pc - 1, self.prefetch.unwrap(), self.status, self.acc, self.x, self.y, self.sp);
+                self.fetch_needed = true;
+            }
+            
+            let opcode = self.prefetch.unwrap();
+            self.prefetch = None;
+            
+            self.procedure = Some(InstructionProcedure::decode(opcode).unwrap_or_else(|| panic!("Attempt to run invalid/unimplemented opcode! PC: {:#06X}, Op: {:#06X}", self.pc, opcode))); // decode opcode into an instruction procedure (this doesn't consume cycles)
             
             // debugging
             if !self.fetch_needed {
@@ -456,28 +542,11 @@ impl Cpu {
         self.sp -= Wrapping(1);
     }
     
-    #[allow(dead_code)]
     fn stack_pull(&mut self, bus: &mut Bus) -> u8 {
         self.sp += Wrapping(1);
         bus.read(0x100 + self.sp.0 as u16)
     }
 }
-impl BusAccessable for Cpu {
-    fn write(&mut self, addr: u16, data: u8) {
-        match addr {
-            0x0100..=0x01FF => self.stack[(addr & 0x00FF) as usize] = data,
-            _ => panic!("Write attempt to invalid address {:#06X} ({:#04X})", addr, data),
-        }
-    }
-
-    fn read(&mut self, addr: u16) -> u8 {
-        match addr {
-            0x0100..=0x01FF => self.stack[(addr & 0x00FF) as usize],
-            _ => panic!("Read attempt to invalid address {:#06X}", addr),
-        }
-    }
-}
-
 fn adc(procedure: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
     if let Some(addr) = effective_addr(procedure, cpu, bus) {
         let data = bus.read(addr);
@@ -885,10 +954,53 @@ fn ora(procedure: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
         procedure.done = true;
     }
 }
-fn pha(procedure: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) { unimplemented!() }
-fn php(procedure: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) { unimplemented!() }
-fn pla(procedure: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) { unimplemented!() }
-fn plp(procedure: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) { unimplemented!() }
+fn pha(procedure: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match procedure.cycle {
+        2 => {bus.read(cpu.pc);},
+        3 => {
+            cpu.stack_push(bus, cpu.acc);
+            procedure.done = true;
+        },
+        _ => ()
+    }
+}
+fn php(procedure: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match procedure.cycle {
+        2 => {bus.read(cpu.pc);},
+        3 => {
+            cpu.stack_push(bus, (cpu.status | StatusReg::Break | StatusReg::Unused).bits());
+            procedure.done = true;
+        },
+        _ => ()
+    }
+}
+fn pla(procedure: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match procedure.cycle {
+        2 => {bus.read(cpu.pc);},
+        3 => {bus.read(0x100 + cpu.sp.0 as u16);},
+        4 => {
+            cpu.acc = cpu.stack_pull(bus);
+            
+            cpu.status.set(StatusReg::Zero, cpu.acc == 0);
+            cpu.status.set(StatusReg::Negative, cpu.acc & 0x80 > 0);
+            cpu.prefetch = Some(cpu.fetch(bus));
+            procedure.done = true;
+        },
+        _ => ()
+    }
+}
+fn plp(procedure: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
+    match procedure.cycle {
+        2 => {bus.read(cpu.pc);},
+        3 => {bus.read(0x100 + cpu.sp.0 as u16);},
+        4 => {
+            cpu.status = StatusReg::from_bits_truncate(cpu.stack_pull(bus)) | StatusReg::Break | StatusReg::Unused;
+            cpu.prefetch = Some(cpu.fetch(bus));
+            procedure.done = true;
+        },
+        _ => ()
+    }
+}
 fn rla(procedure: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) { unimplemented!() }
 fn rra(procedure: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) { unimplemented!() }
 fn rol(procedure: &mut InstructionProcedure, cpu: &mut Cpu, bus: &mut Bus) {
@@ -0,0 +1,73 @@
+use bitflags::bitflags;
+use crate::arch::{Bus, Controller};
+
+bitflags! {
+    /// Directions and button of a joystick that are currently held.
+    #[derive(Default)]
+    pub struct Joystick: u8 {
+        const UP    = 0b00000001;
+        const DOWN  = 0b00000010;
+        const LEFT  = 0b00000100;
+        const RIGHT = 0b00001000;
+        const FIRE  = 0b00010000;
+    }
+}
+
+/// Everything the player can press during a frame: both joysticks, and the console's reset and
+/// select buttons.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Input {
+    pub joysticks: [Joystick; 2],
+    pub reset: bool,
+    pub select: bool,
+}
+impl Input {
+    /// Drives the RIOT and TIA input pins to match. Ports that don't have a joystick plugged in
+    /// are left alone.
+    pub fn apply(&self, bus: &mut Bus) {
+        for (port, joystick) in self.joysticks.iter().enumerate() {
+            if bus.controllers[port] != Controller::Joystick {
+                continue;
+            }
+            
+            // SWCHA holds the directions for both joysticks (P0 in the high nibble), pulled low
+            // while held. The fire buttons go to INPT4/INPT5.
+            let mut pins = 0b1111;
+            if joystick.contains(Joystick::UP) { pins &= !0b0001; }
+            if joystick.contains(Joystick::DOWN) { pins &= !0b0010; }
+            if joystick.contains(Joystick::LEFT) { pins &= !0b0100; }
+            if joystick.contains(Joystick::RIGHT) { pins &= !0b1000; }
+            
+            let shift = if port == 0 { 4 } else { 0 };
+            bus.pia.swcha = (bus.pia.swcha & !(0b1111 << shift)) | (pins << shift);
+            bus.tia.fire[port] = joystick.contains(Joystick::FIRE);
+        }
+        
+        bus.pia.swchb = (bus.pia.swchb & !0b11) | ((!self.select as u8) << 1) | (!self.reset as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn apply() {
+        let mut bus = Bus::default();
+        let input = Input {
+            joysticks: [Joystick::UP | Joystick::FIRE, Joystick::LEFT | Joystick::RIGHT],
+            reset: true,
+            select: false,
+        };
+        input.apply(&mut bus);
+        assert_eq!(bus.pia.swcha, 0b1110_0011);
+        assert_eq!(bus.tia.fire, [true, false]);
+        assert_eq!(bus.pia.swchb & 0b11, 0b10);
+        
+        // only joysticks are driven
+        bus.controllers[1] = Controller::Paddles;
+        Input::default().apply(&mut bus);
+        assert_eq!(bus.pia.swcha, 0b1111_0011);
+        assert_eq!(bus.pia.swchb & 0b11, 0b11);
+    }
+}
\ No newline at end of file
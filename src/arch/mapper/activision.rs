@@ -0,0 +1,80 @@
+use crate::arch::mapper::{Bank, Mapper, Segment};
+use crate::arch::state::impl_savestate;
+
+/// Activision FE scheme. 8K in two 4K banks.
+/// 
+/// There are no hotspots. Instead, the cartridge watches for an access to $01FE, which is where
+/// JSR and RTS put the low byte of the return address when the stack is full. The access right
+/// after it carries the high byte of the jump/return address, and bit 5 of that byte (A13, which
+/// the 6507 doesn't have) selects the bank: set for $Fxxx (bank 0), clear for $Dxxx (bank 1).
+#[derive(Clone, Debug)]
+pub struct Activision {
+    rom: Vec<u8>,
+    bank: usize,
+    last_access_01fe: bool,
+}
+impl Activision {
+    pub fn new(rom: &[u8]) -> Self {
+        let mut rom = rom.to_owned();
+        rom.resize(8192, 0);
+        
+        Self {
+            rom,
+            bank: 0,
+            last_access_01fe: false,
+        }
+    }
+}
+
+impl_savestate!(Activision { bank, last_access_01fe });
+
+impl Mapper for Activision {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.rom[(self.bank * 4096) + (addr & 0x0FFF) as usize]
+    }
+    fn write(&mut self, _addr: u16, _data: u8) {}
+    
+    fn snoop(&mut self, addr: u16, data: u8, _write: bool) {
+        if self.last_access_01fe {
+            self.bank = if data & 0b00100000 != 0 { 0 } else { 1 };
+        }
+        self.last_access_01fe = addr == 0x01FE;
+    }
+    
+    fn segments(&self) -> Vec<Segment> {
+        vec![Segment::new(0x000, 0x1000, Bank::Rom(self.bank))]
+    }
+    
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::{Bus, BusAccessable};
+    use crate::util::InfCell;
+    
+    #[test]
+    fn jsr_switches_banks() {
+        // bank 0: LDX #$FF; TXS; JSR $D010. bank 1 at $D010: LDA #$55; STA $80
+        let mut rom = vec![0xEA; 8192];
+        rom[..6].copy_from_slice(&[0xA2, 0xFF, 0x9A, 0x20, 0x10, 0xD0]);
+        rom[0x1010..0x1014].copy_from_slice(&[0xA9, 0x55, 0x85, 0x80]);
+        rom[0xFFC] = 0x00;
+        rom[0xFFD] = 0xF0;
+        
+        let bus_cell = InfCell::new(Bus::default());
+        let bus = bus_cell.get_mut();
+        bus.cart.set_mapper(Box::new(Activision::new(&rom)));
+        bus.cpu.init_pc(bus_cell.get_mut());
+        for _ in 0..20 {
+            bus.cpu.cycle(&bus_cell);
+        }
+        
+        assert_eq!(bus.cart.segments(), vec![Segment::new(0x000, 0x1000, Bank::Rom(1))]);
+        assert_eq!(bus.read(0x0080), 0x55);
+    }
+}
\ No newline at end of file
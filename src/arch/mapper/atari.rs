@@ -0,0 +1,186 @@
+use crate::arch::mapper::{Bank, Mapper, Segment};
+use crate::arch::state::impl_savestate;
+
+/// Standard Atari bankswitching schemes. Each scheme maps one of several 4K banks into cartridge
+/// space, selected by accessing one of the hotspots at the top of the bank.
+/// 
+/// CBS RAM+ (FA) works the same way, so it is included here as well.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Scheme {
+    /// 8K, 2 banks, hotspots at $1FF8-$1FF9
+    F8,
+    /// 16K, 4 banks, hotspots at $1FF6-$1FF9
+    F6,
+    /// 32K, 8 banks, hotspots at $1FF4-$1FFB
+    F4,
+    /// 12K, 3 banks, hotspots at $1FF8-$1FFA, always has 256 bytes of RAM
+    FA,
+}
+impl Scheme {
+    pub fn bank_count(&self) -> usize {
+        match self {
+            Scheme::F8 => 2,
+            Scheme::F6 => 4,
+            Scheme::F4 => 8,
+            Scheme::FA => 3,
+        }
+    }
+    
+    /// Size of the extra RAM, when the cartridge has any.
+    fn ram_size(&self) -> usize {
+        match self {
+            Scheme::FA => 256,
+            _ => 128,
+        }
+    }
+    
+    fn first_hotspot(&self) -> u16 {
+        match self {
+            Scheme::F8 => 0x1FF8,
+            Scheme::F6 => 0x1FF6,
+            Scheme::F4 => 0x1FF4,
+            Scheme::FA => 0x1FF8,
+        }
+    }
+}
+
+/// F8, F6 or F4 cartridge, optionally with a Superchip (128 bytes of RAM, written through
+/// $1000-$107F and read back through $1080-$10FF), or an FA cartridge (256 bytes of RAM, written
+/// through $1000-$10FF and read back through $1100-$11FF).
+#[derive(Clone, Debug)]
+pub struct Atari {
+    rom: Vec<u8>,
+    scheme: Scheme,
+    bank: usize,
+    ram: Option<Vec<u8>>,
+}
+impl Atari {
+    /// Creates a cartridge of the given scheme. `superchip` is ignored for FA, which always has RAM.
+    pub fn new(rom: &[u8], scheme: Scheme, superchip: bool) -> Self {
+        let mut rom = rom.to_owned();
+        rom.resize(scheme.bank_count() * 4096, 0);
+        
+        Self {
+            rom,
+            scheme,
+            bank: scheme.bank_count() - 1,
+            ram: if superchip || scheme == Scheme::FA { Some(vec![0u8; scheme.ram_size()]) } else { None },
+        }
+    }
+    
+    fn check_hotspot(&mut self, addr: u16) {
+        let first = self.scheme.first_hotspot();
+        if addr >= first && addr < first + self.scheme.bank_count() as u16 {
+            self.bank = (addr - first) as usize;
+        }
+    }
+}
+
+impl_savestate!(Atari { bank, ram });
+
+impl Mapper for Atari {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.check_hotspot(addr);
+        
+        let offset = (addr & 0x0FFF) as usize;
+        match &self.ram {
+            Some(ram) if offset < ram.len() * 2 => ram[offset % ram.len()], // reading the write port isn't really safe on hardware
+            _ => self.rom[(self.bank * 4096) + offset],
+        }
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        self.check_hotspot(addr);
+        
+        let offset = (addr & 0x0FFF) as usize;
+        if let Some(ram) = &mut self.ram {
+            if offset < ram.len() {
+                ram[offset] = data;
+            }
+        }
+    }
+    
+    fn segments(&self) -> Vec<Segment> {
+        match &self.ram {
+            Some(ram) => {
+                let size = (ram.len() * 2) as u16;
+                vec![Segment::new(0x000, size, Bank::Ram(0)), Segment::new(size, 0x1000 - size, Bank::Rom(self.bank))]
+            },
+            None => vec![Segment::new(0x000, 0x1000, Bank::Rom(self.bank))],
+        }
+    }
+    
+    fn ram(&self) -> Option<&[u8]> {
+        self.ram.as_deref()
+    }
+    fn ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.ram.as_deref_mut()
+    }
+    
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::mapper::tests::banked_rom;
+    use crate::arch::state::{Savestate, StateReader, StateWriter};
+    
+    #[test]
+    fn savestate_round_trip() {
+        let rom = banked_rom(2, 4096);
+        let mut cart = Atari::new(&rom, Scheme::F8, true);
+        cart.read(0x1FF8);
+        cart.write(0x1005, 42);
+        let mut w = StateWriter::new();
+        cart.save(&mut w);
+        let saved = w.into_bytes();
+        
+        let mut loaded = Atari::new(&rom, Scheme::F8, true);
+        loaded.load(&mut StateReader::new(&saved)).unwrap();
+        assert_eq!(loaded.ram().unwrap()[5], 42);
+        assert_eq!(loaded.segments(), cart.segments());
+        let mut w = StateWriter::new();
+        loaded.save(&mut w);
+        assert_eq!(w.into_bytes(), saved);
+        assert!(loaded.load(&mut StateReader::new(&saved[..saved.len() - 1])).is_err());
+    }
+    
+    #[test]
+    fn hotspots() {
+        for scheme in [Scheme::F8, Scheme::F6, Scheme::F4] {
+            let mut cart = Atari::new(&banked_rom(scheme.bank_count(), 4096), scheme, false);
+            assert_eq!(cart.read(0x1000), (scheme.bank_count() - 1) as u8, "{:?} doesn't start in the last bank", scheme);
+            for bank in 0..scheme.bank_count() {
+                cart.read(scheme.first_hotspot() + bank as u16);
+                assert_eq!(cart.read(0x1234), bank as u8);
+                cart.write(scheme.first_hotspot() + bank as u16, 0);
+                assert_eq!(cart.segments(), vec![Segment::new(0x000, 0x1000, Bank::Rom(bank))]);
+            }
+        }
+    }
+    
+    #[test]
+    fn superchip() {
+        let mut cart = Atari::new(&banked_rom(2, 4096), Scheme::F8, true);
+        cart.write(0x1005, 0x77);
+        assert_eq!(cart.read(0x1085), 0x77);
+        cart.read(0x1FF8);
+        assert_eq!(cart.read(0x1085), 0x77, "RAM was switched out with the ROM bank");
+        assert_eq!(cart.read(0x1100), 0);
+        cart.write(0x1085, 0x11); // the read port can't be written
+        assert_eq!(cart.read(0x1085), 0x77);
+    }
+    
+    #[test]
+    fn fa() {
+        let mut cart = Atari::new(&banked_rom(3, 4096), Scheme::FA, false);
+        cart.write(0x10FF, 3);
+        assert_eq!(cart.read(0x11FF), 3);
+        cart.read(0x1FF9);
+        assert_eq!(cart.read(0x1200), 1);
+        cart.read(0x1FFB); // only 3 banks
+        assert_eq!(cart.read(0x1200), 1);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,412 @@
+use crate::arch::mapper::harmony::{CPU_CLOCK, FLASH_SIZE, Harmony, SRAM_BASE, SRAM_SIZE, STACK_TOP};
+use crate::arch::mapper::{Bank, Mapper, Segment};
+use crate::arch::mapper::thumb::{ArmError, Thumb, ThumbBus};
+use crate::arch::state::impl_savestate;
+
+/// Size of the ARM driver at the start of the image.
+const DRIVER_SIZE: usize = 0x800;
+/// Offset of the 6507 banks in the image. Custom ARM code starts right after the driver.
+const PROGRAM_OFFSET: usize = 0x1000;
+const ARM_OFFSET: u32 = 0x800;
+/// Offset of the display data in SRAM, which everything after the driver's copy is used for.
+const DISPLAY: usize = 0x800;
+
+const COMM_STREAM: u8 = 0x20;
+const JUMP_STREAM: u8 = 0x21;
+
+/// Rate of the oscillator that drives the music counters.
+const OSC_CLOCK: u32 = 20000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Version {
+    Cdf0,
+    Cdf1,
+    Cdfj,
+}
+impl Version {
+    /// Offsets of the stream pointers, stream increments and waveform pointers in the driver's
+    /// copy in SRAM.
+    fn bases(&self) -> (usize, usize, usize) {
+        match self {
+            Version::Cdf0 => (0x06E0, 0x0768, 0x07F0),
+            Version::Cdf1 => (0x00A0, 0x0128, 0x01B0),
+            Version::Cdfj => (0x0098, 0x0124, 0x01B0),
+        }
+    }
+    
+    /// Stream read by `LDA #` with the music amplitude. Lower values read data streams.
+    fn amplitude_stream(&self) -> u8 {
+        match self {
+            Version::Cdfj => 0x23,
+            _ => 0x22,
+        }
+    }
+    
+    /// CDFJ has a second jump stream, selected by `JMP $0001`.
+    fn jump_mask(&self) -> u8 {
+        match self {
+            Version::Cdfj => 0xFE,
+            _ => 0xFF,
+        }
+    }
+    
+    /// Address of the first callback stub (SetNote) in the driver. The stubs for ResetWave,
+    /// GetWavePtr and SetWaveSize follow it, 4 bytes apart.
+    fn callbacks(&self) -> u32 {
+        match self {
+            Version::Cdf0 => 0x06E2,
+            _ => 0x0752,
+        }
+    }
+}
+
+/// State of the three music voices, which custom ARM code sets up through driver callbacks.
+#[derive(Clone, Debug)]
+struct Music {
+    counters: [u32; 3],
+    frequencies: [u32; 3],
+    waveform_sizes: [u8; 3],
+}
+impl_savestate!(Music { counters, frequencies, waveform_sizes });
+
+/// Memory seen by the ARM, with the driver's music callbacks handled natively.
+struct CdfBus<'a> {
+    harmony: &'a mut Harmony,
+    music: &'a mut Music,
+    callbacks: u32,
+}
+impl ThumbBus for CdfBus<'_> {
+    fn read8(&mut self, addr: u32) -> u8 { self.harmony.read8(addr) }
+    fn read16(&mut self, addr: u32) -> u16 { self.harmony.read16(addr) }
+    fn read32(&mut self, addr: u32) -> u32 { self.harmony.read32(addr) }
+    fn write8(&mut self, addr: u32, data: u8) { self.harmony.write8(addr, data) }
+    fn write16(&mut self, addr: u32, data: u16) { self.harmony.write16(addr, data) }
+    fn write32(&mut self, addr: u32, data: u32) { self.harmony.write32(addr, data) }
+    fn take_fault(&mut self) -> Option<ArmError> { self.harmony.take_fault() }
+    
+    fn arm_call(&mut self, from: u32, _target: u32, regs: &mut [u32; 16]) -> bool {
+        let voice = regs[2] as usize;
+        if from < self.callbacks || voice >= 3 {
+            return false;
+        }
+        
+        match from - self.callbacks {
+            0x0 => self.music.frequencies[voice] = regs[3], // SetNote
+            0x4 => self.music.counters[voice] = 0, // ResetWave
+            0x8 => regs[2] = self.music.counters[voice], // GetWavePtr
+            0xC => self.music.waveform_sizes[voice] = regs[3] as u8, // SetWaveSize
+            _ => return false,
+        }
+        
+        true
+    }
+}
+
+/// CDF and CDFJ (Harmony/Melody), the "Chris, Darrell, Fred" scheme with 32 or more data streams.
+///
+/// The 32K image holds a 2K ARM driver, followed by custom ARM code and seven 4K banks at $1000
+/// (hotspots $1FF5-$1FFB). The driver's 6507 interface is emulated natively, but its register
+/// layout in SRAM is kept, since custom ARM code accesses the stream pointers directly.
+///
+/// - $1FF0 (write): DSWRITE, write to the communication stream
+/// - $1FF1 (write): DSPTR, shift a byte into the communication stream's pointer
+/// - $1FF2 (write): SETMODE, fast fetch (low nibble 0) and digital audio (high nibble 0)
+/// - $1FF3 (write): CALLFN, 254/255 runs custom ARM code from $0808, stalling the 6507. If the
+///   custom code crashes, the ARM is halted and further calls are ignored
+///
+/// In fast fetch mode, the operand of an `LDA #` up to the amplitude stream reads that data
+/// stream instead, and `JMP $0000` takes its destination from the jump stream.
+#[derive(Clone, Debug)]
+pub struct Cdf {
+    harmony: Harmony,
+    arm: Thumb,
+    version: Version,
+    bank: usize,
+    
+    mode: u8,
+    lda_operand: Option<u16>,
+    jump_operand: Option<u16>,
+    jump_remaining: u8,
+    jump_stream: u8,
+    
+    music: Music,
+    osc_counter: u32,
+    
+    stall: u32,
+    halted: bool,
+    /// Error that halted the ARM, until it's been taken to be reported.
+    error: Option<ArmError>,
+}
+impl Cdf {
+    pub fn new(rom: &[u8], version: Version) -> Self {
+        Self {
+            harmony: Harmony::new(rom, DRIVER_SIZE),
+            arm: Thumb::default(),
+            version,
+            bank: 6,
+            
+            mode: 0xFF,
+            lda_operand: None,
+            jump_operand: None,
+            jump_remaining: 0,
+            jump_stream: JUMP_STREAM,
+            
+            music: Music {
+                counters: [0; 3],
+                frequencies: [0; 3],
+                waveform_sizes: [27; 3],
+            },
+            osc_counter: 0,
+            
+            stall: 0,
+            halted: false,
+            error: None,
+        }
+    }
+    
+    /// CDF images have the "CDF" signature three times in a row (every 4 bytes) in the driver,
+    /// with the version after the first one.
+    pub fn detect(rom: &[u8]) -> Option<Version> {
+        let driver = &rom[..DRIVER_SIZE.min(rom.len())];
+        for i in (0..driver.len().saturating_sub(11)).step_by(4) {
+            if (0..3).all(|j| &driver[(i + j * 4)..(i + j * 4 + 3)] == b"CDF") {
+                return Some(match driver[i + 3] {
+                    0x00 => Version::Cdf0,
+                    b'J' => Version::Cdfj,
+                    _ => Version::Cdf1,
+                });
+            }
+        }
+        
+        None
+    }
+    
+    fn fast_fetch(&self) -> bool {
+        self.mode & 0x0F == 0
+    }
+    
+    fn digital_audio(&self) -> bool {
+        self.mode & 0xF0 == 0
+    }
+    
+    fn rom(&self, addr: u16) -> u8 {
+        self.harmony.flash.get(PROGRAM_OFFSET + (self.bank * 4096) + addr as usize).copied().unwrap_or(0)
+    }
+    
+    fn display(&mut self, index: u32) -> &mut u8 {
+        &mut self.harmony.sram[DISPLAY + (index & 0x0FFF) as usize]
+    }
+    
+    fn stream_pointer(&self, stream: u8) -> u32 {
+        self.harmony.sram_u32(self.version.bases().0 + stream as usize * 4)
+    }
+    
+    fn set_stream_pointer(&mut self, stream: u8, pointer: u32) {
+        self.harmony.set_sram_u32(self.version.bases().0 + stream as usize * 4, pointer);
+    }
+    
+    fn stream_increment(&self, stream: u8) -> u32 {
+        self.harmony.sram_u32(self.version.bases().1 + stream as usize * 4) & 0xFFFF
+    }
+    
+    /// Reads a byte from a stream, then advances its 12.20 fixed point pointer.
+    fn read_stream(&mut self, stream: u8, increment: u32) -> u8 {
+        let pointer = self.stream_pointer(stream);
+        let data = *self.display(pointer >> 20);
+        self.set_stream_pointer(stream, pointer.wrapping_add(increment));
+        
+        data
+    }
+    
+    /// Offset of a voice's waveform within the display data.
+    fn waveform(&self, voice: usize) -> u32 {
+        let pointer = self.harmony.sram_u32(self.version.bases().2 + voice * 4);
+        pointer.wrapping_sub(SRAM_BASE + DISPLAY as u32) & 0x0FFF
+    }
+    
+    fn amplitude(&mut self) -> u8 {
+        if self.digital_audio() {
+            // 4-bit samples packed two per byte, anywhere in Flash or SRAM
+            let addr = self.harmony.sram_u32(self.version.bases().2).wrapping_add(self.music.counters[0] >> 21);
+            let data = if (addr as usize) < FLASH_SIZE {
+                self.harmony.flash[addr as usize]
+            } else if addr >= SRAM_BASE && ((addr - SRAM_BASE) as usize) < SRAM_SIZE {
+                self.harmony.sram[(addr - SRAM_BASE) as usize]
+            } else {
+                0
+            };
+            
+            if self.music.counters[0] & (1 << 20) == 0 { data >> 4 } else { data & 0x0F }
+        } else {
+            let mut amplitude = 0u8;
+            for i in 0..3 {
+                let index = self.waveform(i) + (self.music.counters[i] >> self.music.waveform_sizes[i].min(31));
+                amplitude = amplitude.wrapping_add(*self.display(index));
+            }
+            
+            amplitude
+        }
+    }
+    
+    fn check_hotspot(&mut self, addr: u16) {
+        if let 0x0FF5..=0x0FFB = addr {
+            self.bank = (addr - 0x0FF5) as usize;
+        }
+    }
+    
+    fn call_arm(&mut self) {
+        if self.halted {
+            return;
+        }
+        
+        let mut bus = CdfBus {
+            harmony: &mut self.harmony,
+            music: &mut self.music,
+            callbacks: self.version.callbacks(),
+        };
+        
+        match self.arm.run(&mut bus, ARM_OFFSET + 8, ARM_OFFSET, STACK_TOP) {
+            Ok(cycles) => self.stall += Harmony::cpu_cycles(cycles),
+            Err(err) => {
+                self.halted = true;
+                self.error = Some(err);
+            },
+        }
+    }
+}
+
+impl_savestate!(Cdf { harmony, bank, mode, lda_operand, jump_operand, jump_remaining, jump_stream, music, osc_counter, stall, halted });
+
+impl Mapper for Cdf {
+    fn read(&mut self, addr: u16) -> u8 {
+        let addr = addr & 0x0FFF;
+        let data = self.rom(addr);
+        
+        // operand of a fast jump
+        if self.jump_remaining > 0 && self.jump_operand == Some(addr) {
+            self.jump_remaining -= 1;
+            self.jump_operand = Some(addr + 1);
+            return self.read_stream(self.jump_stream, 0x100000);
+        }
+        
+        if self.fast_fetch() && data == 0x4C && self.rom(addr + 1) & self.version.jump_mask() == 0 && self.rom(addr + 2) == 0 {
+            self.jump_remaining = 2;
+            self.jump_operand = Some(addr + 1);
+            self.jump_stream = JUMP_STREAM + (self.rom(addr + 1) & 1);
+            return data;
+        }
+        self.jump_operand = None;
+        
+        if self.fast_fetch() && self.lda_operand == Some(addr) && data <= self.version.amplitude_stream() {
+            self.lda_operand = None;
+            if data == self.version.amplitude_stream() {
+                return self.amplitude();
+            }
+            
+            let increment = self.stream_increment(data) << 12;
+            return self.read_stream(data, increment);
+        }
+        self.lda_operand = None;
+        
+        self.check_hotspot(addr);
+        if self.fast_fetch() && data == 0xA9 {
+            self.lda_operand = Some(addr + 1);
+        }
+        
+        data
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr & 0x0FFF {
+            0x0FF0 => {
+                let pointer = self.stream_pointer(COMM_STREAM);
+                *self.display(pointer >> 20) = data;
+                self.set_stream_pointer(COMM_STREAM, pointer.wrapping_add(0x100000));
+            },
+            0x0FF1 => {
+                let pointer = ((self.stream_pointer(COMM_STREAM) << 8) & 0xF0000000) | ((data as u32) << 20);
+                self.set_stream_pointer(COMM_STREAM, pointer);
+            },
+            0x0FF2 => self.mode = data,
+            0x0FF3 => if data >= 254 {
+                self.call_arm();
+            },
+            addr => self.check_hotspot(addr),
+        }
+    }
+    
+    fn cycle(&mut self) {
+        self.stall = self.stall.saturating_sub(1);
+        
+        self.osc_counter += OSC_CLOCK;
+        if self.osc_counter >= CPU_CLOCK as u32 {
+            self.osc_counter -= CPU_CLOCK as u32;
+            for i in 0..3 {
+                self.music.counters[i] = self.music.counters[i].wrapping_add(self.music.frequencies[i]);
+            }
+        }
+    }
+    
+    fn stalling(&self) -> bool {
+        self.stall > 0
+    }
+    
+    fn take_error(&mut self) -> Option<ArmError> {
+        self.error.take()
+    }
+    
+    fn segments(&self) -> Vec<Segment> {
+        vec![Segment::new(0x000, 0x1000, Bank::Rom(self.bank))]
+    }
+    
+    fn ram(&self) -> Option<&[u8]> {
+        Some(&self.harmony.sram)
+    }
+    fn ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.harmony.sram)
+    }
+    
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// 32K image where every byte of each bank holds the bank's number, with `code` as the custom
+    /// ARM code.
+    fn image(code: &[u16]) -> Vec<u8> {
+        let mut image = vec![0; 32768];
+        for bank in 0..7 {
+            image[(PROGRAM_OFFSET + bank * 4096)..(PROGRAM_OFFSET + (bank + 1) * 4096)].fill(bank as u8);
+        }
+        for (i, op) in code.iter().enumerate() {
+            let offset = ARM_OFFSET as usize + 8 + i * 2;
+            image[offset..(offset + 2)].copy_from_slice(&op.to_le_bytes());
+        }
+        
+        image
+    }
+    
+    #[test]
+    fn hotspots() {
+        let mut cart = Cdf::new(&image(&[]), Version::Cdfj);
+        assert_eq!(cart.read(0x1100), 6);
+        for bank in 0..7 {
+            cart.read(0x1FF5 + bank as u16);
+            assert_eq!(cart.read(0x1100), bank as u8);
+        }
+    }
+    
+    #[test]
+    fn arm_crash_halts() {
+        // MOVS r1, #0; STR r0, [r1]
+        let mut cart = Cdf::new(&image(&[0x2100, 0x6008]), Version::Cdfj);
+        cart.write(0x1FF3, 254); // CALLFN
+        assert_eq!(cart.take_error(), Some(ArmError::FlashWrite(0)));
+        cart.write(0x1FF3, 254);
+        assert_eq!(cart.take_error(), None, "halted ARM ran again");
+        assert!(!cart.stalling());
+    }
+}
\ No newline at end of file
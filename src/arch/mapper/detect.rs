@@ -0,0 +1,378 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use crate::arch::cartridge::CartridgeError;
+use crate::arch::mapper::activision::Activision;
+use crate::arch::mapper::atari::{Atari, Scheme};
+use crate::arch::mapper::cdf::{Cdf, Version};
+use crate::arch::mapper::dpc::Dpc;
+use crate::arch::mapper::dpcplus::DpcPlus;
+use crate::arch::mapper::econobanking::EconoBanking;
+use crate::arch::mapper::Mapper;
+use crate::arch::mapper::mnetwork::MNetwork;
+use crate::arch::mapper::parker::ParkerBros;
+use crate::arch::mapper::standard::Standard;
+use crate::arch::mapper::starpath::{LOAD_SIZE, Supercharger};
+use crate::arch::mapper::tigervision::Tigervision;
+use crate::arch::mapper::ua::UaLtd;
+
+/// Every supported bankswitching scheme, named like Stella does on the command line.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MapperKind {
+    /// 2K or 4K
+    Standard,
+    /// F8, F6, F4 (with or without a Superchip) and FA
+    Atari(Scheme, bool),
+    /// E0
+    ParkerBros,
+    /// E7
+    MNetwork,
+    /// 3F
+    Tigervision,
+    /// 3E
+    TigervisionRam,
+    /// UA
+    UaLtd,
+    /// 0840
+    EconoBanking,
+    /// FE
+    Activision,
+    /// DPC
+    Dpc,
+    /// DPC+
+    DpcPlus,
+    /// CDF, CDFJ
+    Cdf,
+    /// AR
+    Supercharger,
+}
+impl MapperKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MapperKind::Standard => "4K",
+            MapperKind::Atari(Scheme::F8, false) => "F8",
+            MapperKind::Atari(Scheme::F8, true) => "F8SC",
+            MapperKind::Atari(Scheme::F6, false) => "F6",
+            MapperKind::Atari(Scheme::F6, true) => "F6SC",
+            MapperKind::Atari(Scheme::F4, false) => "F4",
+            MapperKind::Atari(Scheme::F4, true) => "F4SC",
+            MapperKind::Atari(Scheme::FA, _) => "FA",
+            MapperKind::ParkerBros => "E0",
+            MapperKind::MNetwork => "E7",
+            MapperKind::Tigervision => "3F",
+            MapperKind::TigervisionRam => "3E",
+            MapperKind::UaLtd => "UA",
+            MapperKind::EconoBanking => "0840",
+            MapperKind::Activision => "FE",
+            MapperKind::Dpc => "DPC",
+            MapperKind::DpcPlus => "DPC+",
+            MapperKind::Cdf => "CDF",
+            MapperKind::Supercharger => "AR",
+        }
+    }
+    
+    /// Whether a ROM of `len` bytes can be used with this scheme.
+    pub fn supports_size(&self, len: usize) -> bool {
+        match self {
+            MapperKind::Standard => len == 2048 || len == 4096,
+            MapperKind::Atari(scheme, _) => len == scheme.bank_count() * 4096,
+            MapperKind::ParkerBros | MapperKind::UaLtd | MapperKind::EconoBanking | MapperKind::Activision => len == 8192,
+            MapperKind::MNetwork => len == 16384,
+            MapperKind::Tigervision | MapperKind::TigervisionRam => len > 0 && len.is_multiple_of(2048) && len <= 512 * 1024,
+            MapperKind::Dpc => len == 10240 || len == 10495,
+            MapperKind::DpcPlus => len == 29696 || len == 32768,
+            MapperKind::Cdf => len == 32768,
+            MapperKind::Supercharger => len > 0 && len.is_multiple_of(LOAD_SIZE),
+        }
+    }
+    
+    /// Creates the mapper for `rom`, after making sure the size makes sense for this scheme.
+    pub fn create(&self, rom: &[u8]) -> Result<Box<dyn Mapper>, CartridgeError> {
+        if !self.supports_size(rom.len()) {
+            return Err(CartridgeError::SizeMismatch(*self, rom.len()));
+        }
+        
+        Ok(match self {
+            MapperKind::Standard => Box::new(Standard::new(rom)),
+            MapperKind::Atari(scheme, superchip) => Box::new(Atari::new(rom, *scheme, *superchip)),
+            MapperKind::ParkerBros => Box::new(ParkerBros::new(rom)),
+            MapperKind::MNetwork => Box::new(MNetwork::new(rom)),
+            MapperKind::Tigervision => Box::new(Tigervision::new(rom)),
+            MapperKind::TigervisionRam => Box::new(Tigervision::new_3e(rom)),
+            MapperKind::UaLtd => Box::new(UaLtd::new(rom)),
+            MapperKind::EconoBanking => Box::new(EconoBanking::new(rom)),
+            MapperKind::Activision => Box::new(Activision::new(rom)),
+            MapperKind::Dpc => Box::new(Dpc::new(rom)),
+            MapperKind::DpcPlus => Box::new(DpcPlus::new(rom)?),
+            MapperKind::Cdf => Box::new(Cdf::new(rom, Cdf::detect(rom).unwrap_or(Version::Cdfj))),
+            MapperKind::Supercharger => Box::new(Supercharger::new(rom)),
+        })
+    }
+}
+impl Display for MapperKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+impl FromStr for MapperKind {
+    type Err = CartridgeError;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_uppercase().as_str() {
+            "2K" | "4K" => MapperKind::Standard,
+            "F8" => MapperKind::Atari(Scheme::F8, false),
+            "F8SC" => MapperKind::Atari(Scheme::F8, true),
+            "F6" => MapperKind::Atari(Scheme::F6, false),
+            "F6SC" => MapperKind::Atari(Scheme::F6, true),
+            "F4" => MapperKind::Atari(Scheme::F4, false),
+            "F4SC" => MapperKind::Atari(Scheme::F4, true),
+            "FA" => MapperKind::Atari(Scheme::FA, true),
+            "E0" => MapperKind::ParkerBros,
+            "E7" => MapperKind::MNetwork,
+            "3F" => MapperKind::Tigervision,
+            "3E" => MapperKind::TigervisionRam,
+            "UA" => MapperKind::UaLtd,
+            "0840" => MapperKind::EconoBanking,
+            "FE" => MapperKind::Activision,
+            "DPC" => MapperKind::Dpc,
+            "DPC+" => MapperKind::DpcPlus,
+            "CDF" | "CDFJ" => MapperKind::Cdf,
+            "AR" => MapperKind::Supercharger,
+            _ => return Err(CartridgeError::UnknownMapper(s.to_owned())),
+        })
+    }
+}
+
+/// How sure the detection is about its result.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub enum Confidence {
+    /// Nothing in the image pointed to the scheme. It's just the most common one for this size.
+    Low,
+    /// Code in the image accesses the scheme's hotspots.
+    Medium,
+    /// The size is unique to the scheme, or the image contains the scheme's signature.
+    High,
+}
+impl Display for Confidence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Confidence::Low => "low",
+            Confidence::Medium => "medium",
+            Confidence::High => "high",
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Detection {
+    pub kind: MapperKind,
+    pub confidence: Confidence,
+}
+impl Detection {
+    fn new(kind: MapperKind, confidence: Confidence) -> Self {
+        Self { kind, confidence }
+    }
+}
+
+// Code sequences which (almost) only appear in games using a particular scheme. These are the
+// same ones Stella looks for.
+const E0_PATTERNS: &[&[u8]] = &[
+    &[0x8D, 0xE0, 0x1F], // STA $1FE0
+    &[0x8D, 0xE0, 0x5F], // STA $5FE0
+    &[0x8D, 0xE9, 0xFF], // STA $FFE9
+    &[0x0C, 0xE0, 0x1F], // NOP $1FE0
+    &[0xAD, 0xE0, 0x1F], // LDA $1FE0
+    &[0xAD, 0xE9, 0xFF], // LDA $FFE9
+    &[0xAD, 0xED, 0xFF], // LDA $FFED
+    &[0xAD, 0xF3, 0xBF], // LDA $BFF3
+];
+const E7_PATTERNS: &[&[u8]] = &[
+    &[0xAD, 0xE2, 0xFF], // LDA $FFE2
+    &[0xAD, 0xE5, 0xFF], // LDA $FFE5
+    &[0xAD, 0xE5, 0x1F], // LDA $1FE5
+    &[0xAD, 0xE7, 0x1F], // LDA $1FE7
+    &[0x0C, 0xE7, 0x1F], // NOP $1FE7
+    &[0x8D, 0xE7, 0xFF], // STA $FFE7
+    &[0x8D, 0xE7, 0x1F], // STA $1FE7
+];
+const UA_PATTERNS: &[&[u8]] = &[
+    &[0x8D, 0x40, 0x02], // STA $240
+    &[0xAD, 0x40, 0x02], // LDA $240
+    &[0xBD, 0x1F, 0x02], // LDA $21F,X
+];
+const FE_PATTERNS: &[&[u8]] = &[
+    &[0x20, 0x00, 0xD0, 0xC6, 0xC5], // JSR $D000; DEC $C5
+    &[0x20, 0xC3, 0xF8, 0xA5, 0x82], // JSR $F8C3; LDA $82
+    &[0xD0, 0xFB, 0x20, 0x73, 0xFE], // BNE $FB; JSR $FE73
+    &[0x20, 0x00, 0xF0, 0x84, 0xD6], // JSR $F000; STY $D6
+];
+const EB_PATTERNS: &[&[u8]] = &[
+    &[0xAD, 0x00, 0x08], // LDA $0800
+    &[0xAD, 0x40, 0x08], // LDA $0840
+    &[0x2C, 0x00, 0x08], // BIT $0800
+    &[0x0C, 0x00, 0x08, 0x4C], // NOP $0800; JMP
+    &[0x0C, 0xFF, 0x0F, 0x4C], // NOP $0FFF; JMP
+];
+const TIGERVISION_PATTERN: &[u8] = &[0x85, 0x3F]; // STA $3F
+const TIGERVISION_RAM_PATTERN: &[u8] = &[0x85, 0x3E, 0xA9, 0x00]; // STA $3E; LDA #$00
+
+fn count(rom: &[u8], pattern: &[u8]) -> usize {
+    rom.windows(pattern.len()).filter(|window| *window == pattern).count()
+}
+
+fn contains_any(rom: &[u8], patterns: &[&[u8]]) -> bool {
+    patterns.iter().any(|pattern| count(rom, pattern) > 0)
+}
+
+/// Games with a Superchip have the RAM area of every bank filled with the same value, since
+/// nothing placed there could be read back.
+fn has_superchip(rom: &[u8]) -> bool {
+    rom.chunks(4096).all(|bank| bank[..256].iter().all(|byte| *byte == bank[0]))
+}
+
+/// Whether any absolute mode load, store, compare or bit test accesses one of `hotspots` (in
+/// any cartridge space mirror).
+fn accesses_hotspots(rom: &[u8], hotspots: std::ops::RangeInclusive<u8>) -> bool {
+    const OPCODES: [u8; 9] = [0xAD, 0xAE, 0xAC, 0x8D, 0x8E, 0x8C, 0x2C, 0x0C, 0xCD];
+    
+    rom.windows(3).any(|window| OPCODES.contains(&window[0]) && hotspots.contains(&window[1]) && window[2] & 0x1F == 0x1F)
+}
+
+/// Picks the most likely scheme for a ROM image, based on its size first and then on the code
+/// inside it.
+pub fn detect(rom: &[u8]) -> Result<Detection, CartridgeError> {
+    use Confidence::*;
+    
+    let atari = |scheme: Scheme, first_hotspot: u8| {
+        if has_superchip(rom) {
+            Detection::new(MapperKind::Atari(scheme, true), Medium)
+        } else if accesses_hotspots(rom, first_hotspot..=0xFB) {
+            Detection::new(MapperKind::Atari(scheme, false), Medium)
+        } else {
+            Detection::new(MapperKind::Atari(scheme, false), Low)
+        }
+    };
+    let tigervision = || {
+        if count(rom, TIGERVISION_RAM_PATTERN) > 0 {
+            Some(Detection::new(MapperKind::TigervisionRam, Medium))
+        } else if count(rom, TIGERVISION_PATTERN) > 1 {
+            Some(Detection::new(MapperKind::Tigervision, Medium))
+        } else {
+            None
+        }
+    };
+    
+    Ok(match rom.len() {
+        2048 | 4096 => Detection::new(MapperKind::Standard, High),
+        8192 => {
+            if has_superchip(rom) {
+                Detection::new(MapperKind::Atari(Scheme::F8, true), Medium)
+            } else if contains_any(rom, E0_PATTERNS) {
+                Detection::new(MapperKind::ParkerBros, Medium)
+            } else if let Some(detection) = tigervision() {
+                detection
+            } else if contains_any(rom, UA_PATTERNS) {
+                Detection::new(MapperKind::UaLtd, Medium)
+            } else if contains_any(rom, FE_PATTERNS) && !accesses_hotspots(rom, 0xF8..=0xF9) {
+                Detection::new(MapperKind::Activision, Medium)
+            } else if contains_any(rom, EB_PATTERNS) {
+                Detection::new(MapperKind::EconoBanking, Medium)
+            } else {
+                atari(Scheme::F8, 0xF8)
+            }
+        },
+        10240 | 10495 => Detection::new(MapperKind::Dpc, High),
+        12288 => Detection::new(MapperKind::Atari(Scheme::FA, true), High),
+        16384 => {
+            if has_superchip(rom) {
+                Detection::new(MapperKind::Atari(Scheme::F6, true), Medium)
+            } else if contains_any(rom, E7_PATTERNS) {
+                Detection::new(MapperKind::MNetwork, Medium)
+            } else if let Some(detection) = tigervision() {
+                detection
+            } else {
+                atari(Scheme::F6, 0xF6)
+            }
+        },
+        29696 => Detection::new(MapperKind::DpcPlus, High),
+        32768 => {
+            if DpcPlus::detect(rom) {
+                Detection::new(MapperKind::DpcPlus, High)
+            } else if Cdf::detect(rom).is_some() {
+                Detection::new(MapperKind::Cdf, High)
+            } else if has_superchip(rom) {
+                Detection::new(MapperKind::Atari(Scheme::F4, true), Medium)
+            } else if let Some(detection) = tigervision() {
+                detection
+            } else {
+                atari(Scheme::F4, 0xF4)
+            }
+        },
+        len if len > 0 && len.is_multiple_of(LOAD_SIZE) => Detection::new(MapperKind::Supercharger, High),
+        len if MapperKind::Tigervision.supports_size(len) => {
+            tigervision().unwrap_or(Detection::new(MapperKind::Tigervision, Low))
+        },
+        len => return Err(CartridgeError::UnsupportedSize(len)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// ROM of NOPs with `code` near the start of the first bank. The first byte of every bank
+    /// differs from the rest, so it doesn't look like it has a Superchip.
+    fn rom(len: usize, code: &[&[u8]]) -> Vec<u8> {
+        let mut rom = vec![0xEA; len];
+        for bank in rom.chunks_mut(4096) {
+            bank[0] = 0x00;
+        }
+        let code = code.concat();
+        rom[0x100..(0x100 + code.len())].copy_from_slice(&code);
+        
+        rom
+    }
+    
+    fn kind(rom: &[u8]) -> MapperKind {
+        detect(rom).unwrap().kind
+    }
+    
+    #[test]
+    fn sizes() {
+        assert_eq!(detect(&rom(2048, &[])).unwrap(), Detection::new(MapperKind::Standard, Confidence::High));
+        assert_eq!(kind(&rom(10240, &[])), MapperKind::Dpc);
+        assert_eq!(kind(&rom(12288, &[])), MapperKind::Atari(Scheme::FA, true));
+        assert_eq!(kind(&rom(LOAD_SIZE * 3, &[])), MapperKind::Supercharger);
+        assert_eq!(kind(&rom(4096 * 16, &[])), MapperKind::Tigervision);
+        assert!(detect(&rom(3000, &[])).is_err());
+    }
+    
+    #[test]
+    fn patterns() {
+        assert_eq!(detect(&rom(8192, &[])).unwrap(), Detection::new(MapperKind::Atari(Scheme::F8, false), Confidence::Low));
+        assert_eq!(detect(&rom(8192, &[&[0x8D, 0xF9, 0x1F]])).unwrap(), Detection::new(MapperKind::Atari(Scheme::F8, false), Confidence::Medium));
+        assert_eq!(kind(&vec![0xEA; 8192]), MapperKind::Atari(Scheme::F8, true));
+        assert_eq!(kind(&rom(8192, &[E0_PATTERNS[0]])), MapperKind::ParkerBros);
+        assert_eq!(kind(&rom(8192, &[TIGERVISION_PATTERN, TIGERVISION_PATTERN])), MapperKind::Tigervision);
+        assert_eq!(kind(&rom(8192, &[TIGERVISION_RAM_PATTERN])), MapperKind::TigervisionRam);
+        assert_eq!(kind(&rom(8192, &[UA_PATTERNS[0]])), MapperKind::UaLtd);
+        assert_eq!(kind(&rom(8192, &[FE_PATTERNS[0]])), MapperKind::Activision);
+        assert_eq!(kind(&rom(8192, &[EB_PATTERNS[0]])), MapperKind::EconoBanking);
+        assert_eq!(kind(&rom(16384, &[E7_PATTERNS[0]])), MapperKind::MNetwork);
+        assert_eq!(kind(&rom(16384, &[])), MapperKind::Atari(Scheme::F6, false));
+        assert_eq!(kind(&rom(32768, &[])), MapperKind::Atari(Scheme::F4, false));
+    }
+    
+    #[test]
+    fn names() {
+        let kinds = [MapperKind::Standard, MapperKind::Atari(Scheme::F8, false), MapperKind::Atari(Scheme::F8, true),
+            MapperKind::Atari(Scheme::F6, false), MapperKind::Atari(Scheme::F6, true), MapperKind::Atari(Scheme::F4, false),
+            MapperKind::Atari(Scheme::F4, true), MapperKind::Atari(Scheme::FA, true), MapperKind::ParkerBros, MapperKind::MNetwork,
+            MapperKind::Tigervision, MapperKind::TigervisionRam, MapperKind::UaLtd, MapperKind::EconoBanking, MapperKind::Activision,
+            MapperKind::Dpc, MapperKind::DpcPlus, MapperKind::Cdf, MapperKind::Supercharger];
+        for kind in kinds {
+            assert_eq!(kind.name().parse::<MapperKind>().unwrap(), kind);
+        }
+        assert_eq!("f8sc".parse::<MapperKind>().unwrap(), MapperKind::Atari(Scheme::F8, true));
+        assert!("F9".parse::<MapperKind>().is_err());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,254 @@
+use crate::arch::mapper::{Bank, Mapper, Segment};
+use crate::arch::state::impl_savestate;
+
+/// CPU clock rate (NTSC), used to derive the DPC's music oscillator.
+const CPU_CLOCK: u32 = 1193182;
+/// Rate of the oscillator that drives the music mode data fetchers.
+const OSC_CLOCK: u32 = 20000;
+
+/// Mixer output for each combination of the three music voices.
+const MUSIC_AMPLITUDES: [u8; 8] = [0x00, 0x04, 0x05, 0x09, 0x06, 0x0A, 0x0B, 0x0F];
+
+/// David Crane's DPC chip, as used by Pitfall II.
+/// 
+/// The image is 8K of program ROM in two 4K banks (hotspots $1FF8-$1FF9), followed by 2K of
+/// display data which is only accessible through the 8 data fetchers.
+/// 
+/// - $1000-$103F (read): random number, music amplitude, display data and flags, selected by
+///   A5-A3 for the data fetcher in A2-A0
+/// - $1040-$107F (write): data fetcher top/bottom/counter registers, music mode and random
+///   number generator reset
+/// 
+/// Data fetchers 5-7 can be switched into music mode, where they are clocked by a ~20KHz
+/// oscillator instead of by reads. The game mixes the three voices by reading the amplitude
+/// register and storing it into AUDV0.
+#[derive(Clone, Debug)]
+pub struct Dpc {
+    rom: Vec<u8>,
+    display: Vec<u8>,
+    bank: usize,
+    
+    tops: [u8; 8],
+    bottoms: [u8; 8],
+    counters: [u16; 8],
+    flags: [u8; 8],
+    music_mode: [bool; 3],
+    random: u8,
+    
+    osc_counter: u32,
+}
+impl Dpc {
+    pub fn new(rom: &[u8]) -> Self {
+        let mut rom = rom.to_owned();
+        rom.resize(8192 + 2048, 0);
+        let display = rom.split_off(8192);
+        
+        Self {
+            rom,
+            display,
+            bank: 1,
+            
+            tops: [0; 8],
+            bottoms: [0; 8],
+            counters: [0; 8],
+            flags: [0; 8],
+            music_mode: [false; 3],
+            random: 1,
+            
+            osc_counter: 0,
+        }
+    }
+    
+    fn check_hotspot(&mut self, addr: u16) {
+        match addr {
+            0x1FF8 => self.bank = 0,
+            0x1FF9 => self.bank = 1,
+            _ => (),
+        }
+    }
+    
+    /// The random number generator is an 8-bit LFSR, clocked on every access to the cartridge.
+    fn clock_random(&mut self) {
+        const FEEDBACK: [u8; 16] = [1, 0, 0, 1, 0, 1, 1, 0, 0, 1, 1, 0, 1, 0, 0, 1];
+        
+        let bit = FEEDBACK[(((self.random >> 3) & 0b0111) | if self.random & 0x80 != 0 { 0b1000 } else { 0 }) as usize];
+        self.random = (self.random << 1) | bit;
+    }
+    
+    /// Clocks the data fetchers that are in music mode by one oscillator tick.
+    fn clock_music(&mut self) {
+        for i in 5..=7 {
+            if !self.music_mode[i - 5] {
+                continue;
+            }
+            
+            let mut low = self.counters[i] & 0x00FF;
+            if self.tops[i] == 0 {
+                low = 0;
+            } else if low == 0 {
+                low = self.tops[i] as u16;
+            } else {
+                low -= 1;
+            }
+            
+            if low <= self.bottoms[i] as u16 {
+                self.flags[i] = 0x00;
+            } else if low <= self.tops[i] as u16 {
+                self.flags[i] = 0xFF;
+            }
+            
+            self.counters[i] = (self.counters[i] & 0x0700) | low;
+        }
+    }
+    
+    fn read_register(&mut self, addr: u16) -> u8 {
+        let index = (addr & 0b111) as usize;
+        let function = (addr >> 3) & 0b111;
+        
+        let low = (self.counters[index] & 0x00FF) as u8;
+        if low == self.tops[index] {
+            self.flags[index] = 0xFF;
+        } else if low == self.bottoms[index] {
+            self.flags[index] = 0x00;
+        }
+        
+        let display = self.display[2047 - self.counters[index] as usize];
+        let data = match function {
+            0 if index < 4 => self.random,
+            0 => {
+                let mut voices = 0;
+                for i in 0..3 {
+                    if self.music_mode[i] && self.flags[5 + i] != 0 {
+                        voices |= 1 << i;
+                    }
+                }
+                
+                MUSIC_AMPLITUDES[voices]
+            },
+            1 => display,
+            2 => display & self.flags[index],
+            7 => self.flags[index],
+            _ => 0,
+        };
+        
+        if index < 5 || !self.music_mode[index - 5] {
+            self.counters[index] = self.counters[index].wrapping_sub(1) & 0x07FF;
+        }
+        
+        data
+    }
+    
+    fn write_register(&mut self, addr: u16, data: u8) {
+        let index = (addr & 0b111) as usize;
+        let function = (addr >> 3) & 0b111;
+        
+        match function {
+            0 => {
+                self.tops[index] = data;
+                self.flags[index] = 0x00;
+            },
+            1 => self.bottoms[index] = data,
+            2 => {
+                // music mode fetchers load the low counter from the top register instead
+                let low = if index >= 5 && self.music_mode[index - 5] { self.tops[index] } else { data };
+                self.counters[index] = (self.counters[index] & 0x0700) | low as u16;
+            },
+            3 => {
+                self.counters[index] = (((data & 0b111) as u16) << 8) | (self.counters[index] & 0x00FF);
+                if index >= 5 {
+                    self.music_mode[index - 5] = data & 0b00010000 != 0;
+                }
+            },
+            6 => self.random = 1,
+            _ => (),
+        }
+    }
+}
+
+impl_savestate!(Dpc { bank, tops, bottoms, counters, flags, music_mode, random, osc_counter });
+
+impl Mapper for Dpc {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.clock_random();
+        
+        match addr & 0x0FFF {
+            0x0000..=0x003F => self.read_register(addr),
+            _ => {
+                self.check_hotspot(addr);
+                self.rom[(self.bank * 4096) + (addr & 0x0FFF) as usize]
+            }
+        }
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        self.clock_random();
+        
+        match addr & 0x0FFF {
+            0x0040..=0x007F => self.write_register(addr, data),
+            _ => self.check_hotspot(addr),
+        }
+    }
+    
+    fn cycle(&mut self) {
+        self.osc_counter += OSC_CLOCK;
+        if self.osc_counter >= CPU_CLOCK {
+            self.osc_counter -= CPU_CLOCK;
+            self.clock_music();
+        }
+    }
+    
+    fn segments(&self) -> Vec<Segment> {
+        vec![Segment::new(0x000, 0x1000, Bank::Rom(self.bank))]
+    }
+    
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::mapper::tests::banked_rom;
+    
+    #[test]
+    fn hotspots() {
+        let mut cart = Dpc::new(&banked_rom(2, 4096));
+        assert_eq!(cart.read(0x1100), 1);
+        cart.read(0x1FF8);
+        assert_eq!(cart.read(0x1100), 0);
+        cart.write(0x1FF9, 0);
+        assert_eq!(cart.read(0x1100), 1);
+    }
+    
+    #[test]
+    fn data_fetcher() {
+        let mut rom = vec![0; 8192 + 2048];
+        rom[8192 + 2047 - 0x105] = 0xAB;
+        rom[8192 + 2047 - 0x104] = 0xCD;
+        let mut cart = Dpc::new(&rom);
+        cart.write(0x1050, 0x05); // DF0 counter low
+        cart.write(0x1058, 0x01); // DF0 counter high
+        assert_eq!(cart.read(0x1008), 0xAB);
+        assert_eq!(cart.read(0x1008), 0xCD);
+    }
+    
+    #[test]
+    fn music_mode() {
+        let mut cart = Dpc::new(&[]);
+        cart.write(0x1045, 4); // DF5 top
+        cart.write(0x104D, 2); // DF5 bottom
+        cart.write(0x105D, 0x10); // DF5 music mode
+        cart.write(0x1055, 0); // DF5 counter low, loaded from the top register
+        
+        // one oscillator tick is about 60 CPU cycles
+        for _ in 0..60 {
+            cart.cycle();
+        }
+        assert_eq!(cart.read(0x1005), MUSIC_AMPLITUDES[0b001]);
+        for _ in 0..60 {
+            cart.cycle();
+        }
+        assert_eq!(cart.read(0x1005), MUSIC_AMPLITUDES[0b000]);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,376 @@
+use crate::arch::cartridge::CartridgeError;
+use crate::arch::mapper::detect::MapperKind;
+use crate::arch::mapper::harmony::{CPU_CLOCK, FLASH_SIZE, Harmony, STACK_TOP};
+use crate::arch::mapper::{Bank, Mapper, Segment};
+use crate::arch::mapper::thumb::{ArmError, Thumb};
+use crate::arch::state::impl_savestate;
+
+/// Size of the ARM driver at the start of the image.
+const DRIVER_SIZE: usize = 0xC00;
+/// Offset of the 6507 banks (and any custom ARM code in them) in the image.
+const PROGRAM_OFFSET: usize = 0xC00;
+/// Offset of the display data (4K) and frequency table (1K) in the image.
+const DATA_OFFSET: usize = 0x6C00;
+/// Offset of the display data in SRAM. The frequency table follows it.
+const DISPLAY: usize = 0xC00;
+const FREQUENCIES: usize = DISPLAY + 0x1000;
+
+/// Rate of the oscillator that drives the music fetchers.
+const OSC_CLOCK: u32 = 20000;
+
+const RANDOM_RESET: u32 = 0x2B435044;
+
+/// DPC+ (Harmony/Melody), an ARM-based successor of the DPC.
+///
+/// The 32K image holds a 3K ARM driver, six 4K banks (hotspots $1FF6-$1FFB), 4K of display data
+/// and a 1K frequency table. The driver's job is emulated natively, with the display data and
+/// frequency table copied into SRAM where both the fetchers and custom ARM code can modify them.
+/// Older 29K images without the driver are also accepted.
+///
+/// - $1000-$1027 (read): random numbers, music amplitude, and the 8 data fetchers (plain,
+///   windowed, fractional and flags)
+/// - $1028-$107F (write): fetcher pointers and limits, fast fetch, function calls, waveforms,
+///   fetcher push/write, random number and note registers
+///
+/// In fast fetch mode, the operand of an `LDA #` below $28 reads that register instead.
+/// CALLFUNCTION 254/255 runs custom ARM code from $0C08 in Flash, stalling the 6507 for as long
+/// as the ARM takes. If the custom code crashes, the ARM is halted and further calls are ignored.
+#[derive(Clone, Debug)]
+pub struct DpcPlus {
+    harmony: Harmony,
+    arm: Thumb,
+    bank: usize,
+    
+    tops: [u8; 8],
+    bottoms: [u8; 8],
+    counters: [u16; 8],
+    fractional_counters: [u32; 8],
+    fractional_increments: [u8; 8],
+    random: u32,
+    
+    fast_fetch: bool,
+    lda_immediate: bool,
+    parameters: [u8; 8],
+    parameter_pointer: usize,
+    
+    waveforms: [u8; 3],
+    music_counters: [u32; 3],
+    music_frequencies: [u32; 3],
+    osc_counter: u32,
+    
+    stall: u32,
+    halted: bool,
+    /// Error that halted the ARM, until it's been taken to be reported.
+    error: Option<ArmError>,
+}
+impl DpcPlus {
+    pub fn new(rom: &[u8]) -> Result<Self, CartridgeError> {
+        if rom.len() > FLASH_SIZE {
+            return Err(CartridgeError::SizeMismatch(MapperKind::DpcPlus, rom.len()));
+        }
+        
+        // images without a driver are placed after where it would've been
+        let mut image = vec![0; FLASH_SIZE - rom.len()];
+        image.extend_from_slice(rom);
+        
+        let mut harmony = Harmony::new(&image, DRIVER_SIZE);
+        harmony.sram[DISPLAY..].copy_from_slice(&image[DATA_OFFSET..]);
+        
+        Ok(Self {
+            harmony,
+            arm: Thumb::default(),
+            bank: 5,
+            
+            tops: [0; 8],
+            bottoms: [0; 8],
+            counters: [0; 8],
+            fractional_counters: [0; 8],
+            fractional_increments: [0; 8],
+            random: RANDOM_RESET,
+            
+            fast_fetch: false,
+            lda_immediate: false,
+            parameters: [0; 8],
+            parameter_pointer: 0,
+            
+            waveforms: [0; 3],
+            music_counters: [0; 3],
+            music_frequencies: [0; 3],
+            osc_counter: 0,
+            
+            stall: 0,
+            halted: false,
+            error: None,
+        })
+    }
+    
+    /// DPC+ images contain the "DPC+" signature (in the driver) at least twice.
+    pub fn detect(rom: &[u8]) -> bool {
+        rom.windows(4).filter(|window| window == b"DPC+").count() >= 2
+    }
+    
+    fn check_hotspot(&mut self, addr: u16) {
+        if let 0x0FF6..=0x0FFB = addr {
+            self.bank = (addr - 0x0FF6) as usize;
+        }
+    }
+    
+    fn display(&mut self, index: u16) -> &mut u8 {
+        &mut self.harmony.sram[DISPLAY + (index & 0x0FFF) as usize]
+    }
+    
+    /// The random number generator is a 32-bit LFSR which is only clocked when read.
+    fn clock_random(&mut self) {
+        self.random = (if self.random & (1 << 10) != 0 { 0x10ADAB1E } else { 0 }) ^ self.random.rotate_right(11);
+    }
+    
+    /// Steps the random number generator backwards.
+    fn clock_random_prior(&mut self) {
+        self.random = if self.random & (1 << 31) != 0 {
+            let value = 0x10ADAB1E ^ self.random;
+            value.rotate_left(11)
+        } else {
+            self.random.rotate_left(11)
+        };
+    }
+    
+    fn read_register(&mut self, addr: u16) -> u8 {
+        let index = (addr & 0b111) as usize;
+        let function = (addr >> 3) & 0b111;
+        
+        let low = (self.counters[index] & 0x00FF) as u8;
+        let flag = if self.tops[index].wrapping_sub(low) > self.tops[index].wrapping_sub(self.bottoms[index]) { 0xFF } else { 0x00 };
+        
+        match (function, index) {
+            (0, 0) => { self.clock_random(); self.random as u8 },
+            (0, 1) => { self.clock_random_prior(); self.random as u8 },
+            (0, 2..=4) => (self.random >> ((index - 1) * 8)) as u8,
+            (0, 5) => {
+                let mut amplitude = 0u8;
+                for i in 0..3 {
+                    let index = ((self.waveforms[i] as u16) << 5) + (self.music_counters[i] >> 27) as u16;
+                    amplitude = amplitude.wrapping_add(*self.display(index));
+                }
+                
+                amplitude
+            },
+            (1, _) | (2, _) => {
+                let data = *self.display(self.counters[index]);
+                self.counters[index] = (self.counters[index] + 1) & 0x0FFF;
+                
+                if function == 2 { data & flag } else { data }
+            },
+            (3, _) => {
+                let data = *self.display((self.fractional_counters[index] >> 8) as u16);
+                self.fractional_counters[index] = (self.fractional_counters[index] + self.fractional_increments[index] as u32) & 0x0FFFFF;
+                
+                data
+            },
+            (4, 0..=3) => flag,
+            _ => 0,
+        }
+    }
+    
+    fn write_register(&mut self, addr: u16, data: u8) {
+        let index = (addr & 0b111) as usize;
+        
+        match ((addr - 0x28) >> 3) & 0b1111 {
+            0x0 => self.fractional_counters[index] = (self.fractional_counters[index] & 0x0F0000) | ((data as u32) << 8),
+            0x1 => self.fractional_counters[index] = (((data & 0x0F) as u32) << 16) | (self.fractional_counters[index] & 0x00FFFF),
+            0x2 => {
+                self.fractional_increments[index] = data;
+                self.fractional_counters[index] &= 0x0FFF00;
+            },
+            0x3 => self.tops[index] = data,
+            0x4 => self.bottoms[index] = data,
+            0x5 => self.counters[index] = (self.counters[index] & 0x0F00) | data as u16,
+            0x6 => match index {
+                0 => self.fast_fetch = data == 0,
+                1 if self.parameter_pointer < 8 => {
+                    self.parameters[self.parameter_pointer] = data;
+                    self.parameter_pointer += 1;
+                },
+                2 => self.call_function(data),
+                5..=7 => self.waveforms[index - 5] = data & 0x7F,
+                _ => (),
+            },
+            0x7 => {
+                self.counters[index] = self.counters[index].wrapping_sub(1) & 0x0FFF;
+                *self.display(self.counters[index]) = data;
+            },
+            0x8 => self.counters[index] = (((data & 0x0F) as u16) << 8) | (self.counters[index] & 0x00FF),
+            0x9 => match index {
+                0 => self.random = RANDOM_RESET,
+                1..=4 => {
+                    let shift = (index - 1) * 8;
+                    self.random = (self.random & !(0xFF << shift)) | ((data as u32) << shift);
+                },
+                _ => self.music_frequencies[index - 5] = self.harmony.sram_u32(FREQUENCIES + ((data as usize) << 2)),
+            },
+            0xA => {
+                *self.display(self.counters[index]) = data;
+                self.counters[index] = (self.counters[index] + 1) & 0x0FFF;
+            },
+            _ => (),
+        }
+    }
+    
+    fn call_function(&mut self, function: u8) {
+        let index = (self.parameters[2] & 0b111) as usize;
+        let length = self.parameters[3] as u16;
+        
+        match function {
+            0 => (),
+            // copy ROM to fetcher
+            1 => {
+                let source = PROGRAM_OFFSET + (((self.parameters[1] as usize) << 8) | self.parameters[0] as usize);
+                for i in 0..length {
+                    let data = self.harmony.flash[(source + i as usize) % self.harmony.flash.len()];
+                    *self.display(self.counters[index] + i) = data;
+                }
+            },
+            // copy value to fetcher
+            2 => {
+                for i in 0..length {
+                    let data = self.parameters[0];
+                    *self.display(self.counters[index] + i) = data;
+                }
+            },
+            // call custom ARM code
+            254 | 255 if !self.halted => {
+                match self.arm.run(&mut self.harmony, PROGRAM_OFFSET as u32 + 8, PROGRAM_OFFSET as u32, STACK_TOP) {
+                    Ok(cycles) => self.stall += Harmony::cpu_cycles(cycles),
+                    Err(err) => {
+                        self.halted = true;
+                        self.error = Some(err);
+                    },
+                }
+                return;
+            },
+            _ => return,
+        }
+        
+        self.parameter_pointer = 0;
+    }
+}
+
+impl_savestate!(DpcPlus { harmony, bank, tops, bottoms, counters, fractional_counters, fractional_increments, random, fast_fetch, lda_immediate, parameters, parameter_pointer, waveforms, music_counters, music_frequencies, osc_counter, stall, halted });
+
+impl Mapper for DpcPlus {
+    fn read(&mut self, addr: u16) -> u8 {
+        let mut addr = addr & 0x0FFF;
+        let data = self.harmony.flash[PROGRAM_OFFSET + (self.bank * 4096) + addr as usize];
+        
+        if self.fast_fetch && self.lda_immediate && data < 0x28 {
+            addr = data as u16;
+        }
+        self.lda_immediate = false;
+        
+        if addr < 0x28 {
+            return self.read_register(addr);
+        }
+        
+        self.check_hotspot(addr);
+        if self.fast_fetch && data == 0xA9 {
+            self.lda_immediate = true;
+        }
+        
+        data
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr & 0x0FFF {
+            addr @ 0x0028..=0x007F => self.write_register(addr, data),
+            addr => self.check_hotspot(addr),
+        }
+    }
+    
+    fn cycle(&mut self) {
+        self.stall = self.stall.saturating_sub(1);
+        
+        self.osc_counter += OSC_CLOCK;
+        if self.osc_counter >= CPU_CLOCK as u32 {
+            self.osc_counter -= CPU_CLOCK as u32;
+            for i in 0..3 {
+                self.music_counters[i] = self.music_counters[i].wrapping_add(self.music_frequencies[i]);
+            }
+        }
+    }
+    
+    fn stalling(&self) -> bool {
+        self.stall > 0
+    }
+    
+    fn take_error(&mut self) -> Option<ArmError> {
+        self.error.take()
+    }
+    
+    fn segments(&self) -> Vec<Segment> {
+        vec![Segment::new(0x000, 0x1000, Bank::Rom(self.bank))]
+    }
+    
+    fn ram(&self) -> Option<&[u8]> {
+        Some(&self.harmony.sram)
+    }
+    fn ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.harmony.sram)
+    }
+    
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::mapper::thumb::ArmError;
+    
+    /// 32K image where every byte of each bank holds the bank's number, with `code` as the custom
+    /// ARM code.
+    fn image(code: &[u16]) -> Vec<u8> {
+        let mut image = vec![0; 32768];
+        for bank in 0..6 {
+            image[(PROGRAM_OFFSET + bank * 4096)..(PROGRAM_OFFSET + (bank + 1) * 4096)].fill(bank as u8);
+        }
+        for (i, op) in code.iter().enumerate() {
+            image[(PROGRAM_OFFSET + 8 + i * 2)..(PROGRAM_OFFSET + 10 + i * 2)].copy_from_slice(&op.to_le_bytes());
+        }
+        
+        image
+    }
+    
+    #[test]
+    fn hotspots() {
+        let mut cart = DpcPlus::new(&image(&[])).unwrap();
+        assert_eq!(cart.read(0x1100), 5);
+        for bank in 0..6 {
+            cart.read(0x1FF6 + bank as u16);
+            assert_eq!(cart.read(0x1100), bank as u8);
+        }
+        cart.write(0x1FF8, 0);
+        assert_eq!(cart.segments(), vec![Segment::new(0x000, 0x1000, Bank::Rom(2))]);
+    }
+    
+    #[test]
+    fn oversized_rom() {
+        assert!(DpcPlus::new(&vec![0; 32768 + 1024]).is_err());
+    }
+    
+    #[test]
+    fn arm_crash_halts() {
+        // BX LR
+        let mut cart = DpcPlus::new(&image(&[0x4770])).unwrap();
+        cart.write(0x105A, 254); // CALLFUNCTION
+        assert!(cart.stalling());
+        assert_eq!(cart.take_error(), None);
+        
+        // undefined instruction
+        let mut cart = DpcPlus::new(&image(&[0xDE00])).unwrap();
+        cart.write(0x105A, 254);
+        assert!(!cart.stalling());
+        assert_eq!(cart.take_error(), Some(ArmError::Undefined(0xDE00, PROGRAM_OFFSET as u32 + 8)));
+        cart.write(0x105A, 255);
+        assert_eq!(cart.take_error(), None, "halted ARM ran again");
+        assert_eq!(cart.read(0x1100), 5);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,66 @@
+use crate::arch::mapper::{Bank, Mapper, Segment};
+use crate::arch::state::impl_savestate;
+
+/// 0840 "EconoBanking" scheme. 8K in two 4K banks, switched by any access to $0800 (bank 0) or
+/// $0840 (bank 1), or any of their mirrors. The hotspots are outside of cartridge space, so they
+/// are picked up by snooping the bus.
+#[derive(Clone, Debug)]
+pub struct EconoBanking {
+    rom: Vec<u8>,
+    bank: usize,
+}
+impl EconoBanking {
+    pub fn new(rom: &[u8]) -> Self {
+        let mut rom = rom.to_owned();
+        rom.resize(8192, 0);
+        
+        Self {
+            rom,
+            bank: 1,
+        }
+    }
+}
+
+impl_savestate!(EconoBanking { bank });
+
+impl Mapper for EconoBanking {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.rom[(self.bank * 4096) + (addr & 0x0FFF) as usize]
+    }
+    fn write(&mut self, _addr: u16, _data: u8) {}
+    
+    fn snoop(&mut self, addr: u16, _data: u8, _write: bool) {
+        match addr & 0x1840 {
+            0x0800 => self.bank = 0,
+            0x0840 => self.bank = 1,
+            _ => (),
+        }
+    }
+    
+    fn segments(&self) -> Vec<Segment> {
+        vec![Segment::new(0x000, 0x1000, Bank::Rom(self.bank))]
+    }
+    
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::mapper::tests::banked_rom;
+    
+    #[test]
+    fn hotspots() {
+        let mut cart = EconoBanking::new(&banked_rom(2, 4096));
+        assert_eq!(cart.read(0x1000), 1);
+        cart.snoop(0x0800, 0, false);
+        assert_eq!(cart.read(0x1000), 0);
+        cart.snoop(0x0C40, 0, true); // mirror of $0840
+        assert_eq!(cart.read(0x1000), 1);
+        cart.snoop(0x0040, 0, false);
+        assert_eq!(cart.read(0x1000), 1);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,136 @@
+use crate::arch::mapper::thumb::{ArmError, ThumbBus};
+use crate::arch::state::impl_savestate;
+
+pub const FLASH_BASE: u32 = 0x00000000;
+pub const FLASH_SIZE: usize = 32 * 1024;
+pub const SRAM_BASE: u32 = 0x40000000;
+pub const SRAM_SIZE: usize = 8 * 1024;
+
+/// Initial stack pointer handed to custom ARM code, just below the top of SRAM like the
+/// Harmony drivers use.
+pub const STACK_TOP: u32 = 0x40001FB4;
+
+/// ARM clock rate of the LPC2103 on Harmony/Melody boards.
+pub const ARM_CLOCK: u64 = 70_000_000;
+/// CPU clock rate (NTSC), used to convert ARM cycles into 6507 cycles.
+pub const CPU_CLOCK: u64 = 1193182;
+
+/// Memory map of the LPC2103 on Harmony/Melody cartridges, as seen by the ARM.
+///
+/// - $00000000-$00007FFF: Flash, holding the whole cartridge image
+/// - $40000000-$40001FFF: SRAM
+/// - $E0000000-$FFFFFFFF: on-chip peripherals (timers, memory accelerator, etc). Writes are
+///   ignored and reads return 0, which is enough for the cartridge drivers.
+///
+/// At power on, the driver in the first part of Flash copies itself into SRAM. The 6507-side
+/// registers of the driver (data fetchers, music state) live in that copy, so [`Harmony::new`]
+/// does the same.
+///
+/// Accesses to unmapped addresses and writes to Flash are ignored (reading 0), and reported as a
+/// fault to the interpreter.
+#[derive(Clone, Debug)]
+pub struct Harmony {
+    pub flash: Vec<u8>,
+    pub sram: Vec<u8>,
+    fault: Option<ArmError>,
+}
+impl_savestate!(Harmony { sram }); // flash is read-only
+impl Harmony {
+    pub fn new(image: &[u8], driver_size: usize) -> Self {
+        let mut flash = image.to_owned();
+        flash.resize(FLASH_SIZE, 0);
+        
+        let mut sram = vec![0; SRAM_SIZE];
+        sram[..driver_size].copy_from_slice(&flash[..driver_size]);
+        
+        Self {
+            flash,
+            sram,
+            fault: None,
+        }
+    }
+    
+    /// Converts a number of ARM cycles into the number of 6507 cycles that pass in that time.
+    pub fn cpu_cycles(arm_cycles: u64) -> u32 {
+        (arm_cycles * CPU_CLOCK).div_ceil(ARM_CLOCK) as u32
+    }
+    
+    pub fn sram_u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes([self.sram[offset], self.sram[offset + 1], self.sram[offset + 2], self.sram[offset + 3]])
+    }
+    
+    pub fn set_sram_u32(&mut self, offset: usize, data: u32) {
+        self.sram[offset..(offset + 4)].copy_from_slice(&data.to_le_bytes());
+    }
+    
+    /// Returns the memory region and offset for an ARM address, or None for peripherals and
+    /// unmapped addresses.
+    fn decode(&mut self, addr: u32, len: usize) -> Option<(&mut Vec<u8>, usize)> {
+        if addr >= 0xE0000000 {
+            return None;
+        }
+        
+        let (region, offset) = if (addr as usize) < FLASH_SIZE {
+            (&mut self.flash, (addr - FLASH_BASE) as usize)
+        } else if addr >= SRAM_BASE && ((addr - SRAM_BASE) as usize) < SRAM_SIZE {
+            (&mut self.sram, (addr - SRAM_BASE) as usize)
+        } else {
+            self.fault.get_or_insert(ArmError::Unmapped(addr));
+            return None;
+        };
+        
+        if offset + len > region.len() {
+            self.fault.get_or_insert(ArmError::Unmapped(addr));
+            return None;
+        }
+        
+        Some((region, offset))
+    }
+    
+    fn read(&mut self, addr: u32, len: usize) -> u32 {
+        match self.decode(addr, len) {
+            Some((region, offset)) => {
+                let mut bytes = [0u8; 4];
+                bytes[..len].copy_from_slice(&region[offset..(offset + len)]);
+                u32::from_le_bytes(bytes)
+            },
+            None => 0,
+        }
+    }
+    
+    fn write(&mut self, addr: u32, len: usize, data: u32) {
+        if (addr as usize) < FLASH_SIZE {
+            self.fault.get_or_insert(ArmError::FlashWrite(addr));
+            return;
+        }
+        
+        if let Some((region, offset)) = self.decode(addr, len) {
+            region[offset..(offset + len)].copy_from_slice(&data.to_le_bytes()[..len]);
+        }
+    }
+}
+
+impl ThumbBus for Harmony {
+    fn read8(&mut self, addr: u32) -> u8 {
+        self.read(addr, 1) as u8
+    }
+    fn read16(&mut self, addr: u32) -> u16 {
+        self.read(addr, 2) as u16
+    }
+    fn read32(&mut self, addr: u32) -> u32 {
+        self.read(addr, 4)
+    }
+    fn write8(&mut self, addr: u32, data: u8) {
+        self.write(addr, 1, data as u32);
+    }
+    fn write16(&mut self, addr: u32, data: u16) {
+        self.write(addr, 2, data as u32);
+    }
+    fn write32(&mut self, addr: u32, data: u32) {
+        self.write(addr, 4, data);
+    }
+    
+    fn take_fault(&mut self) -> Option<ArmError> {
+        self.fault.take()
+    }
+}
\ No newline at end of file
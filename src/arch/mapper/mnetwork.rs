@@ -0,0 +1,134 @@
+use crate::arch::mapper::{Bank, Mapper, Segment};
+use crate::arch::state::impl_savestate;
+
+/// M-Network E7 scheme. The 16K image is split into eight 2K banks, and the cartridge has 2K of
+/// RAM, split into one 1K bank and four 256 byte banks.
+/// 
+/// - $1000-$17FF: ROM bank 0-6 (hotspots $1FE0-$1FE6), or the 1K RAM bank (hotspot $1FE7),
+///   written through $1000-$13FF and read back through $1400-$17FF
+/// - $1800-$19FF: one of the 256 byte RAM banks (hotspots $1FE8-$1FEB), written through
+///   $1800-$18FF and read back through $1900-$19FF
+/// - $1A00-$1FFF: always the last 1.5K of ROM bank 7
+#[derive(Clone, Debug)]
+pub struct MNetwork {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    bank: usize,
+    ram_selected: bool,
+    ram_bank: usize,
+}
+impl MNetwork {
+    pub fn new(rom: &[u8]) -> Self {
+        let mut rom = rom.to_owned();
+        rom.resize(16384, 0);
+        
+        Self {
+            rom,
+            ram: vec![0u8; 2048],
+            bank: 0,
+            ram_selected: false,
+            ram_bank: 0,
+        }
+    }
+    
+    fn check_hotspot(&mut self, addr: u16) {
+        match addr {
+            0x1FE0..=0x1FE6 => {
+                self.bank = (addr & 0b111) as usize;
+                self.ram_selected = false;
+            },
+            0x1FE7 => self.ram_selected = true,
+            0x1FE8..=0x1FEB => self.ram_bank = (addr & 0b11) as usize,
+            _ => (),
+        }
+    }
+    
+    /// Index into `ram` for an address in one of the RAM windows.
+    fn ram_index(&self, addr: u16) -> Option<usize> {
+        match addr & 0x0FFF {
+            0x0000..=0x07FF if self.ram_selected => Some((addr & 0x03FF) as usize),
+            0x0800..=0x09FF => Some(1024 + (self.ram_bank * 256) + (addr & 0x00FF) as usize),
+            _ => None,
+        }
+    }
+}
+
+impl_savestate!(MNetwork { ram, bank, ram_selected, ram_bank });
+
+impl Mapper for MNetwork {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.check_hotspot(addr);
+        
+        match (self.ram_index(addr), addr & 0x0FFF) {
+            (Some(index), _) => self.ram[index], // reading the write port isn't really safe on hardware
+            (None, 0x0000..=0x07FF) => self.rom[(self.bank * 2048) + (addr & 0x07FF) as usize],
+            (None, _) => self.rom[(7 * 2048) + (addr & 0x07FF) as usize],
+        }
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        self.check_hotspot(addr);
+        
+        let write_port = match addr & 0x0FFF {
+            0x0000..=0x03FF => self.ram_selected,
+            0x0800..=0x08FF => true,
+            _ => false,
+        };
+        if let (true, Some(index)) = (write_port, self.ram_index(addr)) {
+            self.ram[index] = data;
+        }
+    }
+    
+    fn segments(&self) -> Vec<Segment> {
+        // RAM bank 0 is the 1K bank, banks 1-4 are the 256 byte banks
+        vec![
+            Segment::new(0x000, 0x800, if self.ram_selected { Bank::Ram(0) } else { Bank::Rom(self.bank) }),
+            Segment::new(0x800, 0x200, Bank::Ram(1 + self.ram_bank)),
+            Segment::new(0xA00, 0x600, Bank::Rom(7)),
+        ]
+    }
+    
+    fn ram(&self) -> Option<&[u8]> {
+        Some(&self.ram)
+    }
+    fn ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.ram)
+    }
+    
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::mapper::tests::banked_rom;
+    
+    #[test]
+    fn hotspots() {
+        let mut cart = MNetwork::new(&banked_rom(8, 2048));
+        assert_eq!(cart.read(0x1000), 0);
+        assert_eq!(cart.read(0x1A00), 7);
+        cart.read(0x1FE5);
+        assert_eq!(cart.read(0x1000), 5);
+        
+        cart.read(0x1FE7);
+        cart.write(0x1001, 9);
+        assert_eq!(cart.read(0x1401), 9);
+        cart.write(0x1401, 3); // the read port can't be written
+        assert_eq!(cart.read(0x1401), 9);
+        
+        cart.read(0x1FE9);
+        cart.write(0x1801, 7);
+        assert_eq!(cart.read(0x1901), 7);
+        cart.read(0x1FE8);
+        assert_eq!(cart.read(0x1901), 0);
+        cart.write(0x1FE1, 0);
+        assert_eq!(cart.segments(), vec![
+            Segment::new(0x000, 0x800, Bank::Rom(1)),
+            Segment::new(0x800, 0x200, Bank::Ram(1)),
+            Segment::new(0xA00, 0x600, Bank::Rom(7)),
+        ]);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,109 @@
+use std::fmt::{Debug, Display, Formatter};
+use crate::arch::mapper::thumb::ArmError;
+use crate::arch::state::Savestate;
+
+pub mod standard;
+pub mod atari;
+pub mod parker;
+pub mod tigervision;
+pub mod mnetwork;
+pub mod ua;
+pub mod econobanking;
+pub mod activision;
+pub mod dpc;
+pub mod dpcplus;
+pub mod cdf;
+pub mod starpath;
+pub mod detect;
+pub mod harmony;
+pub mod thumb;
+
+/// ROM or RAM bank mapped into part of cartridge space. Banks are numbered the way the scheme
+/// numbers them, counting from the first bank of ROM or cartridge RAM.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Bank {
+    Rom(usize),
+    Ram(usize),
+}
+
+/// Part of cartridge space and the bank currently mapped into it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Segment {
+    /// Offset into cartridge space ($000-$FFF).
+    pub start: u16,
+    pub size: u16,
+    pub bank: Bank,
+}
+impl Segment {
+    pub fn new(start: u16, size: u16, bank: Bank) -> Self {
+        Self { start, size, bank }
+    }
+}
+impl Display for Segment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // shown at the $F000 mirror, which is where most code runs from
+        let start = 0xF000 | self.start;
+        let (kind, bank) = match self.bank {
+            Bank::Rom(bank) => ("ROM", bank),
+            Bank::Ram(bank) => ("RAM", bank),
+        };
+        
+        write!(f, "${:04X}-${:04X}: {} bank {}", start, start + (self.size - 1), kind, bank)
+    }
+}
+
+/// Bankswitching scheme (and any extra hardware) of a cartridge.
+/// 
+/// Every mapper receives all accesses to cartridge space ($1000-$1FFF after masking to 13 bits).
+/// Hotspots must trigger on both reads and writes, since the CPU can't tell them apart.
+/// 
+/// The [`Savestate`] impl covers everything that can change while running (bank selection,
+/// RAM, registers), but not the ROM image.
+pub trait Mapper: Debug + Savestate {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, data: u8);
+    
+    /// Called after every access on the bus, including those outside of cartridge space, with
+    /// the value that ended up on the data bus. Schemes that switch banks by watching TIA, RIOT or
+    /// stack accesses do so here.
+    fn snoop(&mut self, _addr: u16, _data: u8, _write: bool) {}
+    
+    /// Called once per CPU cycle, for cartridges with their own clocked hardware.
+    fn cycle(&mut self) {}
+    
+    /// Whether the cartridge is holding the CPU, like ARM-based cartridges do while their
+    /// coprocessor runs.
+    fn stalling(&self) -> bool { false }
+    
+    /// Takes the next pending write into RIOT RAM, for cartridges that fill in RAM themselves
+    /// (like the Supercharger's BIOS does after a load). Returns the address ($80-$FF) and data.
+    fn take_ram_poke(&mut self) -> Option<(u8, u8)> { None }
+    
+    /// Takes the error that just halted the cartridge's coprocessor, if there was one. ARM-based
+    /// cartridges stop running their custom code when it crashes, instead of crashing the
+    /// emulator with it.
+    fn take_error(&mut self) -> Option<ArmError> { None }
+    
+    /// Banks currently mapped into cartridge space, in address order.
+    fn segments(&self) -> Vec<Segment>;
+    
+    /// RAM on the cartridge, for cartridges that have any.
+    fn ram(&self) -> Option<&[u8]> { None }
+    fn ram_mut(&mut self) -> Option<&mut [u8]> { None }
+    
+    fn clone_box(&self) -> Box<dyn Mapper>;
+}
+impl Clone for Box<dyn Mapper> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    /// ROM image where every byte of each bank holds the bank's number.
+    pub fn banked_rom(banks: usize, bank_size: usize) -> Vec<u8> {
+        (0..(banks * bank_size)).map(|i| (i / bank_size) as u8).collect()
+    }
+}
\ No newline at end of file
@@ -0,0 +1,73 @@
+use crate::arch::mapper::{Bank, Mapper, Segment};
+use crate::arch::state::impl_savestate;
+
+/// Parker Brothers E0 scheme. The 8K image is split into eight 1K slices. The first three 1K
+/// segments of cartridge space can each be pointed at any slice, while the last segment is
+/// always slice 7.
+/// 
+/// Hotspots: $1FE0-$1FE7 select the slice for segment 0, $1FE8-$1FEF for segment 1 and
+/// $1FF0-$1FF7 for segment 2.
+#[derive(Clone, Debug)]
+pub struct ParkerBros {
+    rom: Vec<u8>,
+    segments: [usize; 4],
+}
+impl ParkerBros {
+    pub fn new(rom: &[u8]) -> Self {
+        let mut rom = rom.to_owned();
+        rom.resize(8192, 0);
+        
+        Self {
+            rom,
+            segments: [4, 5, 6, 7],
+        }
+    }
+    
+    fn check_hotspot(&mut self, addr: u16) {
+        if let 0x1FE0..=0x1FF7 = addr {
+            let segment = ((addr >> 3) & 0b11) as usize;
+            self.segments[segment] = (addr & 0b111) as usize;
+        }
+    }
+}
+
+impl_savestate!(ParkerBros { segments });
+
+impl Mapper for ParkerBros {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.check_hotspot(addr);
+        
+        let segment = ((addr >> 10) & 0b11) as usize;
+        self.rom[(self.segments[segment] * 1024) + (addr & 0x03FF) as usize]
+    }
+    fn write(&mut self, addr: u16, _data: u8) {
+        self.check_hotspot(addr);
+    }
+    
+    fn segments(&self) -> Vec<Segment> {
+        self.segments.iter().enumerate().map(|(i, &slice)| Segment::new(i as u16 * 0x400, 0x400, Bank::Rom(slice))).collect()
+    }
+    
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::mapper::tests::banked_rom;
+    
+    #[test]
+    fn hotspots() {
+        let mut cart = ParkerBros::new(&banked_rom(8, 1024));
+        assert_eq!([cart.read(0x1000), cart.read(0x1400), cart.read(0x1800), cart.read(0x1C00)], [4, 5, 6, 7]);
+        cart.read(0x1FE2);
+        cart.write(0x1FE8, 0);
+        cart.read(0x1FF7);
+        assert_eq!([cart.read(0x1000), cart.read(0x1400), cart.read(0x1800), cart.read(0x1C00)], [2, 0, 7, 7]);
+        cart.read(0x1FF8); // past the hotspots, the last segment is fixed
+        assert_eq!(cart.read(0x1C00), 7);
+    }
+}
\ No newline at end of file
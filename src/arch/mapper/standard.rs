@@ -0,0 +1,59 @@
+use crate::arch::mapper::{Bank, Mapper, Segment};
+use crate::arch::state::impl_savestate;
+
+/// Plain 2K or 4K cartridge without any bankswitching. 2K images are mirrored into both halves
+/// of cartridge space.
+#[derive(Clone, Debug)]
+pub struct Standard {
+    rom: Vec<u8>,
+}
+impl Standard {
+    pub fn new(rom: &[u8]) -> Self {
+        let mut rom = rom.to_owned();
+        if rom.len() <= 2048 {
+            rom.resize(2048, 0);
+            rom.extend_from_within(..);
+        }
+        rom.resize(4096, 0);
+        
+        Self { rom }
+    }
+}
+
+impl_savestate!(Standard {});
+
+impl Mapper for Standard {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.rom[(addr & 0x0FFF) as usize]
+    }
+    fn write(&mut self, _addr: u16, _data: u8) {}
+    
+    fn segments(&self) -> Vec<Segment> {
+        vec![Segment::new(0x000, 0x1000, Bank::Rom(0))]
+    }
+    
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn mirroring() {
+        let rom: Vec<u8> = (0..2048).map(|i| (i / 8) as u8).collect();
+        let mut cart = Standard::new(&rom);
+        for addr in 0x1000..0x1800 {
+            assert_eq!(cart.read(addr), cart.read(addr + 0x800), "2K image isn't mirrored at ${:04X}", addr + 0x800);
+        }
+        
+        let rom: Vec<u8> = (0..4096).map(|i| (i / 16) as u8).collect();
+        let mut cart = Standard::new(&rom);
+        assert_eq!(cart.read(0x1FFF), 0xFF);
+        cart.write(0x1FFF, 0);
+        assert_eq!(cart.read(0x1FFF), 0xFF);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,261 @@
+use std::collections::VecDeque;
+use crate::arch::mapper::{Bank, Mapper, Segment};
+use crate::arch::state::impl_savestate;
+
+/// Size of one load in a tape image: 8K of data (32 pages) followed by a 256 byte header.
+pub const LOAD_SIZE: usize = 8448;
+
+/// Stand-in for the Supercharger BIOS, which loads instantly instead of reading from tape.
+///
+/// - $F800: multiload entry, copies the requested load number from $FA to $80
+/// - $F807: reset, clears RAM (so the first load is load 0)
+/// - $F812: reads $F850 to load, then copies a small trampoline into RAM at $F0 which applies the
+///   load's bank configuration and jumps to its start address
+const BIOS_STUB: [u8; 49] = [
+    0xA5, 0xFA,       // $F800: LDA $FA
+    0x85, 0x80,       //        STA $80
+    0x4C, 0x12, 0xF8, //        JMP $F812
+    0x78,             // $F807: SEI
+    0xD8,             //        CLD
+    0xA9, 0x00,       //        LDA #$00
+    0xA2, 0x80,       //        LDX #$80
+    0x95, 0x00,       // $F80D: STA $00,X
+    0xE8,             //        INX
+    0xD0, 0xFB,       //        BNE $F80D
+    0xD8,             // $F812: CLD
+    0xA2, 0xFF,       //        LDX #$FF
+    0x9A,             //        TXS
+    0xAD, 0x50, 0xF8, //        LDA $F850
+    0xA2, 0x0A,       //        LDX #$0A
+    0xBD, 0x26, 0xF8, // $F81B: LDA $F826,X
+    0x95, 0xF0,       //        STA $F0,X
+    0xCA,             //        DEX
+    0x10, 0xF8,       //        BPL $F81B
+    0x4C, 0xF0, 0x00, //        JMP $00F0
+    
+    // trampoline, run from $F0
+    0xA6, 0x80,       //        LDX $80
+    0xDD, 0x00, 0xF0, //        CMP $F000,X
+    0xCD, 0xF8, 0xFF, //        CMP $FFF8
+    0x6C, 0xFE, 0x00, //        JMP ($00FE)
+];
+
+/// Starpath Supercharger (AR), which plugs into the cartridge slot and loads games from tape.
+///
+/// The Supercharger has 6K of RAM in three 2K banks and a 2K BIOS ROM, mapped into $1000-$17FF
+/// and $1800-$1FFF by the configuration byte (bits 4-2). Bit 1 enables RAM writes. Bit 0 powers
+/// off the BIOS ROM, which isn't emulated.
+///
+/// RAM can't be written directly, since the cartridge slot has no R/W line. Instead an access to
+/// $1000-$10FF latches the low address byte into the data hold register, and the 5th distinct
+/// bus access after that writes it to RAM (if it's in cartridge space). Accessing $1FF8 within
+/// that window writes the configuration byte instead.
+///
+/// Tape images hold one or more loads. When the BIOS reads $1850, the load numbered by RAM $80 is
+/// copied into the RAM banks, and like the real BIOS would have, its start address is put in
+/// RAM $FE/$FF and its configuration byte in $80.
+#[derive(Clone, Debug)]
+pub struct Supercharger {
+    loads: Vec<u8>,
+    ram: Vec<u8>,
+    bios: Vec<u8>,
+    offsets: [usize; 2],
+    
+    write_enabled: bool,
+    data_hold: u8,
+    write_pending: bool,
+    hold_access: u64,
+    
+    accesses: u64,
+    last_addr: u16,
+    /// Copy of RAM $80, which is where the BIOS keeps the load number.
+    load_number: u8,
+    pokes: VecDeque<(u8, u8)>,
+}
+impl Supercharger {
+    pub fn new(rom: &[u8]) -> Self {
+        let mut bios = BIOS_STUB.to_vec();
+        bios.resize(2048, 0);
+        bios[0x07FC] = 0x07; // reset vector: $F807
+        bios[0x07FD] = 0xF8;
+        
+        Self::with_loader(rom, &bios)
+    }
+    
+    /// Uses a different 2K fast loader in place of the BIOS, instead of the built-in one. It has to
+    /// follow the same convention: read $1850 to load, then use the values placed in $FE, $FF and
+    /// $80. Dumps of the real BIOS won't work, since they read the tape's audio through $1FF9,
+    /// which isn't emulated.
+    pub fn with_loader(rom: &[u8], loader: &[u8]) -> Self {
+        let mut loads = rom.to_owned();
+        loads.resize(rom.len().div_ceil(LOAD_SIZE) * LOAD_SIZE, 0);
+        let mut bios = loader.to_owned();
+        bios.resize(2048, 0);
+        
+        let mut mapper = Self {
+            loads,
+            ram: vec![0; 6144],
+            bios,
+            offsets: [0; 2],
+            
+            write_enabled: false,
+            data_hold: 0,
+            write_pending: false,
+            hold_access: 0,
+            
+            accesses: 0,
+            last_addr: 0,
+            load_number: 0,
+            pokes: VecDeque::new(),
+        };
+        mapper.configure(0);
+        
+        mapper
+    }
+    
+    fn configure(&mut self, config: u8) {
+        self.write_enabled = config & 0b10 != 0;
+        
+        // bank 3 is the BIOS
+        self.offsets = match (config >> 2) & 0b111 {
+            0 => [2, 3],
+            1 => [0, 2],
+            2 => [2, 0],
+            3 => [0, 3],
+            4 => [2, 1],
+            5 => [1, 2],
+            6 => [2, 3],
+            _ => [1, 3],
+        };
+    }
+    
+    /// Copies a load from the tape image into RAM, if there is one with that number.
+    fn load(&mut self, number: u8) {
+        let load = match self.loads.chunks(LOAD_SIZE).find(|load| load[8192 + 5] == number) {
+            Some(load) => load,
+            None => return,
+        };
+        let header = &load[8192..];
+        
+        for i in 0..header[3] as usize {
+            let bank = (header[16 + i] & 0b11) as usize;
+            let page = ((header[16 + i] >> 2) & 0b111) as usize;
+            if bank < 3 {
+                let dest = (bank * 2048) + (page * 256);
+                self.ram[dest..(dest + 256)].copy_from_slice(&load[(i * 256)..((i + 1) * 256)]);
+            }
+        }
+        
+        self.pokes.extend([(0xFE, header[0]), (0xFF, header[1]), (0x80, header[2])]);
+        self.load_number = header[2];
+    }
+    
+    /// Handles the data hold register and configuration hotspot, shared by reads and writes.
+    fn access(&mut self, addr: u16) {
+        // distinct accesses, counting this one
+        let count = self.accesses + (addr != self.last_addr) as u64;
+        
+        if self.write_pending && count > self.hold_access + 5 {
+            self.write_pending = false;
+        }
+        
+        if addr & 0x0F00 == 0 && (!self.write_enabled || !self.write_pending) {
+            self.data_hold = addr as u8;
+            self.hold_access = count;
+            self.write_pending = true;
+        } else if addr == 0x1FF8 {
+            self.write_pending = false;
+            self.configure(self.data_hold);
+        } else if self.write_enabled && self.write_pending && count == self.hold_access + 5 {
+            let bank = self.offsets[((addr >> 11) & 1) as usize];
+            if bank < 3 {
+                self.ram[(bank * 2048) + (addr & 0x07FF) as usize] = self.data_hold;
+            }
+            self.write_pending = false;
+        }
+    }
+}
+
+impl_savestate!(Supercharger { ram, offsets, write_enabled, data_hold, write_pending, hold_access, accesses, last_addr, load_number, pokes });
+
+impl Mapper for Supercharger {
+    fn read(&mut self, addr: u16) -> u8 {
+        if addr == 0x1850 && self.offsets[1] == 3 {
+            self.load(self.load_number);
+        } else {
+            self.access(addr);
+        }
+        
+        match self.offsets[((addr >> 11) & 1) as usize] {
+            3 => self.bios[(addr & 0x07FF) as usize],
+            bank => self.ram[(bank * 2048) + (addr & 0x07FF) as usize],
+        }
+    }
+    fn write(&mut self, addr: u16, _data: u8) {
+        self.access(addr);
+    }
+    
+    fn snoop(&mut self, addr: u16, data: u8, write: bool) {
+        if addr != self.last_addr {
+            self.accesses += 1;
+            self.last_addr = addr;
+        }
+        
+        // RIOT RAM $80, and its stack page mirror
+        if write && addr & 0x1280 == 0x0080 && addr & 0x007F == 0 {
+            self.load_number = data;
+        }
+    }
+    
+    fn take_ram_poke(&mut self) -> Option<(u8, u8)> {
+        self.pokes.pop_front()
+    }
+    
+    fn segments(&self) -> Vec<Segment> {
+        // the BIOS is the only ROM
+        self.offsets.iter().enumerate().map(|(i, &bank)| {
+            Segment::new(i as u16 * 0x800, 0x800, if bank == 3 { Bank::Rom(0) } else { Bank::Ram(bank) })
+        }).collect()
+    }
+    
+    fn ram(&self) -> Option<&[u8]> {
+        Some(&self.ram)
+    }
+    fn ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.ram)
+    }
+    
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::{Bus, BusAccessable};
+    use crate::util::InfCell;
+    
+    #[test]
+    fn load_and_write_ram() {
+        // CMP $F055 (latch $55); NOP; CMP $F200 (5th access, write it); LDA $F200; STA $81; JMP $F10C
+        let program = [0xCD, 0x55, 0xF0, 0xEA, 0xCD, 0x00, 0xF2, 0xAD, 0x00, 0xF2, 0x85, 0x81, 0x4C, 0x0C, 0xF1];
+        let mut tape = vec![0; LOAD_SIZE];
+        tape[0x100..(0x100 + program.len())].copy_from_slice(&program);
+        let header = &mut tape[8192..];
+        header[..4].copy_from_slice(&[0x00, 0xF1, 0b00001110, 2]); // start at $F100, RAM bank 0 and the BIOS, writes enabled, 2 pages
+        header[16..18].copy_from_slice(&[0x00, 0x04]); // pages 0 and 1 (bits 4-2) of bank 0 (bits 1-0)
+        
+        let bus_cell = InfCell::new(Bus::default());
+        let bus = bus_cell.get_mut();
+        bus.cart.set_mapper(Box::new(Supercharger::new(&tape)));
+        bus.cpu.init_pc(bus_cell.get_mut());
+        for _ in 0..3000 {
+            bus.cpu.cycle(&bus_cell);
+        }
+        
+        assert_eq!([bus.read(0x00FE), bus.read(0x00FF)], [0x00, 0xF1]);
+        assert_eq!(bus.cart.segments(), vec![Segment::new(0x000, 0x800, Bank::Ram(0)), Segment::new(0x800, 0x800, Bank::Rom(0))]);
+        assert_eq!(bus.read(0x0081), 0x55);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,538 @@
+use std::fmt::{Display, Formatter};
+
+/// Reason a routine was stopped before it returned. These come from bugs in the cartridge's code
+/// (or from things that aren't emulated), so they're reported instead of bringing down the
+/// emulator.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ArmError {
+    /// Access to an address where there's no memory or peripheral.
+    Unmapped(u32),
+    /// Write to Flash, which is read-only while running.
+    FlashWrite(u32),
+    /// Undefined instruction, and its address.
+    Undefined(u16, u32),
+    /// Software interrupt, with its comment field and address. There's nothing to handle it.
+    SoftwareInterrupt(u8, u32),
+    /// The routine at the entry address hadn't returned after `MAX_INSTRUCTIONS`, and was at PC.
+    Timeout(u32, u32),
+}
+impl Display for ArmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArmError::Unmapped(addr) => write!(f, "ARM: access to unmapped address {:#010X}", addr),
+            ArmError::FlashWrite(addr) => write!(f, "ARM: write to flash at {:#010X}", addr),
+            ArmError::Undefined(op, addr) => write!(f, "ARM: undefined instruction {:#06X} at {:#010X}", op, addr),
+            ArmError::SoftwareInterrupt(comment, addr) => write!(f, "ARM: software interrupt {:#04X} at {:#010X}", comment, addr),
+            ArmError::Timeout(entry, pc) => write!(f, "ARM: routine at {:#010X} did not return after {} instructions (PC: {:#010X})", entry, MAX_INSTRUCTIONS, pc),
+        }
+    }
+}
+impl std::error::Error for ArmError {}
+
+/// Memory and system interface seen by the Thumb interpreter.
+pub trait ThumbBus {
+    fn read8(&mut self, addr: u32) -> u8;
+    fn read16(&mut self, addr: u32) -> u16;
+    fn read32(&mut self, addr: u32) -> u32;
+    fn write8(&mut self, addr: u32, data: u8);
+    fn write16(&mut self, addr: u32, data: u16);
+    fn write32(&mut self, addr: u32, data: u32);
+    
+    /// Called when the code at `from` branches into ARM (32-bit) code at `target`, which the
+    /// interpreter can't run. Returns true if the call was handled, in which case execution
+    /// continues at the link register. Otherwise the interpreter stops.
+    fn arm_call(&mut self, _from: u32, _target: u32, _regs: &mut [u32; 16]) -> bool {
+        false
+    }
+    
+    /// Takes the fault caused by the last memory access, if there was one. Accesses can't fail
+    /// on their own, so the interpreter checks for faults after every instruction.
+    fn take_fault(&mut self) -> Option<ArmError> {
+        None
+    }
+}
+
+/// Upper bound on instructions per call, so a runaway routine can't hang the emulator.
+const MAX_INSTRUCTIONS: usize = 10_000_000;
+
+/// Interpreter for the 16-bit Thumb instruction set of the ARM7TDMI, as found in the LPC2103 on
+/// Harmony/Melody cartridges.
+///
+/// Only Thumb state is supported. A branch into ARM state is handed to [`ThumbBus::arm_call`],
+/// and if that doesn't handle it, the routine is considered finished. Cartridge drivers rely on
+/// this by setting the link register to an (even) ARM address before calling custom code.
+///
+/// Cycle counts are an approximation of a 70MHz ARM7TDMI running from flash with the memory
+/// accelerator enabled: one cycle per instruction, plus extra cycles for memory accesses and for
+/// refilling the pipeline after a branch.
+#[derive(Clone, Debug, Default)]
+pub struct Thumb {
+    pub regs: [u32; 16],
+    n: bool,
+    z: bool,
+    c: bool,
+    v: bool,
+    
+    pc: u32,
+    cycles: u64,
+}
+impl Thumb {
+    /// Runs the Thumb routine at `entry` until it branches into ARM code. All registers are reset
+    /// beforehand, apart from the stack pointer and link register which are set to `sp` and `lr`.
+    ///
+    /// Returns the number of ARM cycles that were used, or why the routine was stopped.
+    pub fn run(&mut self, bus: &mut dyn ThumbBus, entry: u32, lr: u32, sp: u32) -> Result<u64, ArmError> {
+        self.regs = [0; 16];
+        self.regs[13] = sp;
+        self.regs[14] = lr;
+        self.n = false;
+        self.z = false;
+        self.c = false;
+        self.v = false;
+        self.pc = entry & !1;
+        self.cycles = 0;
+        
+        for _ in 0..MAX_INSTRUCTIONS {
+            let addr = self.pc;
+            let op = bus.read16(addr);
+            self.pc = addr.wrapping_add(2);
+            self.regs[15] = addr.wrapping_add(4); // reads of PC see the instruction address + 4
+            self.cycles += 1;
+            
+            let running = self.execute(bus, addr, op)?;
+            if let Some(fault) = bus.take_fault() {
+                return Err(fault);
+            }
+            if !running {
+                return Ok(self.cycles);
+            }
+        }
+        
+        Err(ArmError::Timeout(entry, self.pc))
+    }
+    
+    fn branch(&mut self, target: u32) {
+        self.pc = target & !1;
+        self.cycles += 2; // pipeline refill
+    }
+    
+    fn set_nz(&mut self, result: u32) {
+        self.n = result & 0x80000000 != 0;
+        self.z = result == 0;
+    }
+    
+    fn add_flags(&mut self, a: u32, b: u32, carry: bool) -> u32 {
+        let result = (a as u64) + (b as u64) + (carry as u64);
+        let r = result as u32;
+        
+        self.set_nz(r);
+        self.c = result > 0xFFFFFFFF;
+        self.v = (!(a ^ b) & (a ^ r)) & 0x80000000 != 0;
+        r
+    }
+    
+    fn sub_flags(&mut self, a: u32, b: u32, carry: bool) -> u32 {
+        self.add_flags(a, !b, carry)
+    }
+    
+    fn condition(&self, cond: u16) -> bool {
+        match cond {
+            0x0 => self.z,
+            0x1 => !self.z,
+            0x2 => self.c,
+            0x3 => !self.c,
+            0x4 => self.n,
+            0x5 => !self.n,
+            0x6 => self.v,
+            0x7 => !self.v,
+            0x8 => self.c && !self.z,
+            0x9 => !self.c || self.z,
+            0xA => self.n == self.v,
+            0xB => self.n != self.v,
+            0xC => !self.z && self.n == self.v,
+            0xD => self.z || self.n != self.v,
+            _ => true,
+        }
+    }
+    
+    /// Shifts `value` by a register amount, as done by the ALU shift instructions.
+    fn shift(&mut self, kind: u16, value: u32, amount: u32) -> u32 {
+        if amount == 0 {
+            return value;
+        }
+        
+        match kind {
+            0 => { // LSL
+                match amount {
+                    1..=31 => { self.c = value & (1 << (32 - amount)) != 0; value << amount },
+                    32 => { self.c = value & 1 != 0; 0 },
+                    _ => { self.c = false; 0 },
+                }
+            },
+            1 => { // LSR
+                match amount {
+                    1..=31 => { self.c = value & (1 << (amount - 1)) != 0; value >> amount },
+                    32 => { self.c = value & 0x80000000 != 0; 0 },
+                    _ => { self.c = false; 0 },
+                }
+            },
+            2 => { // ASR
+                match amount {
+                    1..=31 => { self.c = value & (1 << (amount - 1)) != 0; ((value as i32) >> amount) as u32 },
+                    _ => { self.c = value & 0x80000000 != 0; ((value as i32) >> 31) as u32 },
+                }
+            },
+            _ => { // ROR
+                let amount = amount & 31;
+                if amount == 0 {
+                    self.c = value & 0x80000000 != 0;
+                    value
+                } else {
+                    let result = value.rotate_right(amount);
+                    self.c = result & 0x80000000 != 0;
+                    result
+                }
+            },
+        }
+    }
+    
+    /// Executes one instruction. Returns false once the routine has finished.
+    fn execute(&mut self, bus: &mut dyn ThumbBus, addr: u32, op: u16) -> Result<bool, ArmError> {
+        let rd = (op & 0b111) as usize;
+        let rs = ((op >> 3) & 0b111) as usize;
+        
+        match op >> 11 {
+            // move shifted register
+            0b00000..=0b00010 => {
+                let amount = ((op >> 6) & 0b11111) as u32;
+                let value = self.regs[rs];
+                let result = match (op >> 11, amount) {
+                    (0, _) => self.shift(0, value, amount),
+                    (kind, 0) => self.shift(kind, value, 32), // LSR/ASR #0 encode a shift by 32
+                    (kind, _) => self.shift(kind, value, amount),
+                };
+                self.regs[rd] = result;
+                self.set_nz(result);
+            },
+            
+            // add/subtract
+            0b00011 => {
+                let field = ((op >> 6) & 0b111) as u32;
+                let operand = if op & 0x0400 != 0 { field } else { self.regs[field as usize] };
+                self.regs[rd] = if op & 0x0200 != 0 {
+                    self.sub_flags(self.regs[rs], operand, true)
+                } else {
+                    self.add_flags(self.regs[rs], operand, false)
+                };
+            },
+            
+            // move/compare/add/subtract immediate
+            0b00100..=0b00111 => {
+                let rd = ((op >> 8) & 0b111) as usize;
+                let imm = (op & 0xFF) as u32;
+                match (op >> 11) & 0b11 {
+                    0 => { self.regs[rd] = imm; self.set_nz(imm); },
+                    1 => { self.sub_flags(self.regs[rd], imm, true); },
+                    2 => self.regs[rd] = self.add_flags(self.regs[rd], imm, false),
+                    _ => self.regs[rd] = self.sub_flags(self.regs[rd], imm, true),
+                }
+            },
+            
+            0b01000 => {
+                if op & 0x0400 == 0 {
+                    self.alu(op, rd, rs);
+                } else {
+                    return Ok(self.hi_register(bus, addr, op));
+                }
+            },
+            
+            // PC-relative load
+            0b01001 => {
+                let rd = ((op >> 8) & 0b111) as usize;
+                let addr = (self.regs[15] & !0b11).wrapping_add(((op & 0xFF) as u32) << 2);
+                self.regs[rd] = bus.read32(addr);
+                self.cycles += 2;
+            },
+            
+            // load/store with register offset, load/store sign-extended byte/halfword
+            0b01010 | 0b01011 => {
+                let ro = ((op >> 6) & 0b111) as usize;
+                let addr = self.regs[rs].wrapping_add(self.regs[ro]);
+                if op & 0x0200 == 0 {
+                    match (op >> 10) & 0b11 {
+                        0b00 => { bus.write32(addr & !0b11, self.regs[rd]); self.cycles += 1; },
+                        0b01 => { bus.write8(addr, self.regs[rd] as u8); self.cycles += 1; },
+                        0b10 => { self.regs[rd] = bus.read32(addr & !0b11); self.cycles += 2; },
+                        _ => { self.regs[rd] = bus.read8(addr) as u32; self.cycles += 2; },
+                    }
+                } else {
+                    match (op >> 10) & 0b11 {
+                        0b00 => { bus.write16(addr & !0b1, self.regs[rd] as u16); self.cycles += 1; },
+                        0b01 => { self.regs[rd] = bus.read8(addr) as i8 as i32 as u32; self.cycles += 2; },
+                        0b10 => { self.regs[rd] = bus.read16(addr & !0b1) as u32; self.cycles += 2; },
+                        _ => { self.regs[rd] = bus.read16(addr & !0b1) as i16 as i32 as u32; self.cycles += 2; },
+                    }
+                }
+            },
+            
+            // load/store with immediate offset
+            0b01100..=0b01111 => {
+                let offset = ((op >> 6) & 0b11111) as u32;
+                let byte = op & 0x1000 != 0;
+                let load = op & 0x0800 != 0;
+                let addr = self.regs[rs].wrapping_add(if byte { offset } else { offset << 2 });
+                match (byte, load) {
+                    (false, false) => { bus.write32(addr & !0b11, self.regs[rd]); self.cycles += 1; },
+                    (false, true) => { self.regs[rd] = bus.read32(addr & !0b11); self.cycles += 2; },
+                    (true, false) => { bus.write8(addr, self.regs[rd] as u8); self.cycles += 1; },
+                    (true, true) => { self.regs[rd] = bus.read8(addr) as u32; self.cycles += 2; },
+                }
+            },
+            
+            // load/store halfword
+            0b10000 | 0b10001 => {
+                let addr = self.regs[rs].wrapping_add(((op >> 6) & 0b11111) as u32 * 2) & !0b1;
+                if op & 0x0800 != 0 {
+                    self.regs[rd] = bus.read16(addr) as u32;
+                    self.cycles += 2;
+                } else {
+                    bus.write16(addr, self.regs[rd] as u16);
+                    self.cycles += 1;
+                }
+            },
+            
+            // SP-relative load/store
+            0b10010 | 0b10011 => {
+                let rd = ((op >> 8) & 0b111) as usize;
+                let addr = self.regs[13].wrapping_add(((op & 0xFF) as u32) << 2) & !0b11;
+                if op & 0x0800 != 0 {
+                    self.regs[rd] = bus.read32(addr);
+                    self.cycles += 2;
+                } else {
+                    bus.write32(addr, self.regs[rd]);
+                    self.cycles += 1;
+                }
+            },
+            
+            // load address
+            0b10100 | 0b10101 => {
+                let rd = ((op >> 8) & 0b111) as usize;
+                let base = if op & 0x0800 != 0 { self.regs[13] } else { self.regs[15] & !0b11 };
+                self.regs[rd] = base.wrapping_add(((op & 0xFF) as u32) << 2);
+            },
+            
+            0b10110 | 0b10111 => {
+                match (op >> 8) & 0b1111 {
+                    // add offset to stack pointer
+                    0b0000 => {
+                        let offset = ((op & 0x7F) as u32) << 2;
+                        self.regs[13] = if op & 0x80 != 0 { self.regs[13].wrapping_sub(offset) } else { self.regs[13].wrapping_add(offset) };
+                    },
+                    // push registers
+                    0b0100 | 0b0101 => {
+                        let count = (op & 0xFF).count_ones() + ((op >> 8) & 1) as u32;
+                        let mut addr = self.regs[13].wrapping_sub(count * 4);
+                        self.regs[13] = addr;
+                        for i in 0..8 {
+                            if op & (1 << i) != 0 {
+                                bus.write32(addr, self.regs[i]);
+                                addr = addr.wrapping_add(4);
+                            }
+                        }
+                        if op & 0x0100 != 0 {
+                            bus.write32(addr, self.regs[14]);
+                        }
+                        self.cycles += count as u64;
+                    },
+                    // pop registers
+                    0b1100 | 0b1101 => {
+                        let mut addr = self.regs[13];
+                        for i in 0..8 {
+                            if op & (1 << i) != 0 {
+                                self.regs[i] = bus.read32(addr);
+                                addr = addr.wrapping_add(4);
+                                self.cycles += 1;
+                            }
+                        }
+                        self.cycles += 1;
+                        if op & 0x0100 != 0 {
+                            let target = bus.read32(addr);
+                            self.regs[13] = addr.wrapping_add(4);
+                            self.cycles += 1;
+                            return Ok(self.exchange(bus, addr, target));
+                        }
+                        self.regs[13] = addr;
+                    },
+                    _ => return Err(ArmError::Undefined(op, addr)),
+                }
+            },
+            
+            // multiple load/store
+            0b11000 | 0b11001 => {
+                let rb = ((op >> 8) & 0b111) as usize;
+                let mut addr = self.regs[rb] & !0b11;
+                for i in 0..8 {
+                    if op & (1 << i) != 0 {
+                        if op & 0x0800 != 0 {
+                            self.regs[i] = bus.read32(addr);
+                        } else {
+                            bus.write32(addr, self.regs[i]);
+                        }
+                        addr = addr.wrapping_add(4);
+                        self.cycles += 1;
+                    }
+                }
+                if op & 0x0800 == 0 || op & (1 << rb) == 0 {
+                    self.regs[rb] = addr;
+                }
+                self.cycles += 1;
+            },
+            
+            // conditional branch, software interrupt
+            0b11010 | 0b11011 => {
+                let cond = (op >> 8) & 0b1111;
+                match cond {
+                    0xE => return Err(ArmError::Undefined(op, addr)),
+                    0xF => return Err(ArmError::SoftwareInterrupt(op as u8, addr)),
+                    _ => {
+                        if self.condition(cond) {
+                            let offset = ((op & 0xFF) as i8 as i32) << 1;
+                            self.branch(self.regs[15].wrapping_add(offset as u32));
+                        }
+                    },
+                }
+            },
+            
+            // unconditional branch
+            0b11100 => {
+                let offset = (((op & 0x07FF) as i32) << 21) >> 20;
+                self.branch(self.regs[15].wrapping_add(offset as u32));
+            },
+            
+            // long branch with link
+            0b11110 => {
+                let offset = (((op & 0x07FF) as i32) << 21) >> 9;
+                self.regs[14] = self.regs[15].wrapping_add(offset as u32);
+            },
+            0b11111 => {
+                let target = self.regs[14].wrapping_add(((op & 0x07FF) as u32) << 1);
+                self.regs[14] = self.pc | 1;
+                self.branch(target);
+            },
+            
+            _ => return Err(ArmError::Undefined(op, addr)),
+        }
+        
+        Ok(true)
+    }
+    
+    fn alu(&mut self, op: u16, rd: usize, rs: usize) {
+        let a = self.regs[rd];
+        let b = self.regs[rs];
+        
+        let result = match (op >> 6) & 0b1111 {
+            0x0 => a & b, // AND
+            0x1 => a ^ b, // EOR
+            0x2 => self.shift(0, a, b & 0xFF), // LSL
+            0x3 => self.shift(1, a, b & 0xFF), // LSR
+            0x4 => self.shift(2, a, b & 0xFF), // ASR
+            0x5 => self.add_flags(a, b, self.c), // ADC
+            0x6 => self.sub_flags(a, b, self.c), // SBC
+            0x7 => self.shift(3, a, b & 0xFF), // ROR
+            0x8 => { self.set_nz(a & b); return; }, // TST
+            0x9 => self.sub_flags(0, b, true), // NEG
+            0xA => { self.sub_flags(a, b, true); return; }, // CMP
+            0xB => { self.add_flags(a, b, false); return; }, // CMN
+            0xC => a | b, // ORR
+            0xD => { self.cycles += 2; a.wrapping_mul(b) }, // MUL
+            0xE => a & !b, // BIC
+            _ => !b, // MVN
+        };
+        
+        self.regs[rd] = result;
+        self.set_nz(result);
+    }
+    
+    fn hi_register(&mut self, bus: &mut dyn ThumbBus, addr: u32, op: u16) -> bool {
+        let rd = ((op & 0b111) | ((op >> 4) & 0b1000)) as usize;
+        let rs = ((op >> 3) & 0b1111) as usize;
+        
+        match (op >> 8) & 0b11 {
+            0 => { // ADD
+                let result = self.regs[rd].wrapping_add(self.regs[rs]);
+                if rd == 15 {
+                    self.branch(result);
+                } else {
+                    self.regs[rd] = result;
+                }
+            },
+            1 => { self.sub_flags(self.regs[rd], self.regs[rs], true); }, // CMP
+            2 => { // MOV
+                if rd == 15 {
+                    self.branch(self.regs[rs]);
+                } else {
+                    self.regs[rd] = self.regs[rs];
+                }
+            },
+            _ => return self.exchange(bus, addr, self.regs[rs]), // BX
+        }
+        
+        true
+    }
+    
+    /// Branches to `target`, switching to ARM state if bit 0 is clear.
+    fn exchange(&mut self, bus: &mut dyn ThumbBus, addr: u32, target: u32) -> bool {
+        if target & 1 != 0 {
+            self.branch(target);
+            return true;
+        }
+        
+        if bus.arm_call(addr, target, &mut self.regs) {
+            self.branch(self.regs[14]);
+            return true;
+        }
+        
+        false
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::mapper::harmony::{Harmony, STACK_TOP};
+    
+    /// Runs `code`, placed at $0800 in Flash, until it returns to ARM code at $0700.
+    fn run(code: &[u16]) -> (Thumb, Harmony, Result<u64, ArmError>) {
+        let mut image = vec![0; 0x800];
+        image.extend(code.iter().flat_map(|op| op.to_le_bytes()));
+        let mut harmony = Harmony::new(&image, 0x800);
+        let mut thumb = Thumb::default();
+        let result = thumb.run(&mut harmony, 0x800, 0x700, STACK_TOP);
+        
+        (thumb, harmony, result)
+    }
+    
+    #[test]
+    fn routine() {
+        // PUSH {LR}; MOVS r0, #5; ADDS r0, #3; MOVS r1, #1; LSLS r1, r1, #30; STR r0, [r1]; BL +4; POP {PC}; (padding); MOVS r2, #7; BX LR
+        let (thumb, harmony, result) = run(&[0xB500, 0x2005, 0x3003, 0x2101, 0x0789, 0x6008, 0xF000, 0xF802, 0xBD00, 0x0000, 0x2207, 0x4770]);
+        assert!(result.unwrap() > 0);
+        assert_eq!(harmony.sram_u32(0), 8);
+        assert_eq!(thumb.regs[2], 7);
+        assert_eq!(thumb.regs[13], STACK_TOP);
+    }
+    
+    #[test]
+    fn faults() {
+        assert_eq!(run(&[0xDE00]).2, Err(ArmError::Undefined(0xDE00, 0x800)));
+        assert_eq!(run(&[0xB800]).2, Err(ArmError::Undefined(0xB800, 0x800)));
+        assert_eq!(run(&[0xDF12]).2, Err(ArmError::SoftwareInterrupt(0x12, 0x800)));
+        // MOVS r1, #1; LSLS r1, r1, #29; LDR r0, [r1]
+        assert_eq!(run(&[0x2101, 0x0749, 0x6808]).2, Err(ArmError::Unmapped(0x20000000)));
+        // MOVS r1, #0; STR r0, [r1]
+        assert_eq!(run(&[0x2100, 0x6008]).2, Err(ArmError::FlashWrite(0)));
+        // B .
+        assert_eq!(run(&[0xE7FE]).2, Err(ArmError::Timeout(0x800, 0x800)));
+    }
+}
\ No newline at end of file
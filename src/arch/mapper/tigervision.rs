@@ -0,0 +1,153 @@
+use crate::arch::mapper::{Bank, Mapper, Segment};
+use crate::arch::state::impl_savestate;
+
+/// Tigervision 3F scheme, and the 3E extension of it.
+/// 
+/// The image is split into 2K banks. $1800-$1FFF is always the last bank, while the bank at
+/// $1000-$17FF is selected by writing its number to any address in $00-$3F. Since those are
+/// TIA addresses, the switch is picked up by snooping the bus.
+/// 
+/// 3E adds 32K of RAM, selected in 1K banks by writing to $3E. A selected RAM bank is read
+/// through $1000-$13FF and written through $1400-$17FF, until a ROM bank is selected again
+/// through $3F. On 3E, only $3E and $3F switch banks, so that the game can still write to the
+/// rest of the TIA registers.
+#[derive(Clone, Debug)]
+pub struct Tigervision {
+    rom: Vec<u8>,
+    bank: usize,
+    ram: Option<Vec<u8>>,
+    ram_bank: Option<usize>,
+}
+impl Tigervision {
+    /// Creates a 3F cartridge.
+    pub fn new(rom: &[u8]) -> Self {
+        let mut rom = rom.to_owned();
+        let len = rom.len().div_ceil(2048).max(1) * 2048;
+        rom.resize(len, 0);
+        
+        Self {
+            rom,
+            bank: 0,
+            ram: None,
+            ram_bank: None,
+        }
+    }
+    
+    /// Creates a 3E cartridge, which is a 3F cartridge with 32K of RAM.
+    pub fn new_3e(rom: &[u8]) -> Self {
+        Self {
+            ram: Some(vec![0u8; 32 * 1024]),
+            ..Self::new(rom)
+        }
+    }
+    
+    fn bank_count(&self) -> usize {
+        self.rom.len() / 2048
+    }
+}
+
+impl_savestate!(Tigervision { bank, ram, ram_bank });
+
+impl Mapper for Tigervision {
+    fn read(&mut self, addr: u16) -> u8 {
+        match (&self.ram, self.ram_bank, addr & 0x0FFF) {
+            (Some(ram), Some(bank), 0x0000..=0x03FF) => ram[(bank * 1024) + (addr & 0x03FF) as usize],
+            (Some(ram), Some(bank), 0x0400..=0x07FF) => ram[(bank * 1024) + (addr & 0x03FF) as usize], // reading the write port isn't really safe on hardware
+            (_, _, 0x0000..=0x07FF) => self.rom[(self.bank * 2048) + (addr & 0x07FF) as usize],
+            _ => self.rom[self.rom.len() - 2048 + (addr & 0x07FF) as usize],
+        }
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        if let (Some(ram), Some(bank), 0x0400..=0x07FF) = (&mut self.ram, self.ram_bank, addr & 0x0FFF) {
+            ram[(bank * 1024) + (addr & 0x03FF) as usize] = data;
+        }
+    }
+    
+    fn snoop(&mut self, addr: u16, data: u8, write: bool) {
+        if !write {
+            return;
+        }
+        
+        match (addr, self.ram.is_some()) {
+            (0x003E, true) => self.ram_bank = Some((data & 0x1F) as usize),
+            (0x003F, _) | (0x0000..=0x003E, false) => {
+                self.bank = data as usize % self.bank_count();
+                self.ram_bank = None;
+            },
+            _ => (),
+        }
+    }
+    
+    fn segments(&self) -> Vec<Segment> {
+        let low = match self.ram_bank {
+            Some(bank) if self.ram.is_some() => Segment::new(0x000, 0x800, Bank::Ram(bank)),
+            _ => Segment::new(0x000, 0x800, Bank::Rom(self.bank)),
+        };
+        
+        vec![low, Segment::new(0x800, 0x800, Bank::Rom(self.bank_count() - 1))]
+    }
+    
+    fn ram(&self) -> Option<&[u8]> {
+        self.ram.as_deref()
+    }
+    fn ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.ram.as_deref_mut()
+    }
+    
+    fn clone_box(&self) -> Box<dyn Mapper> {
+        Box::new(self.clone())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::{Bus, BusAccessable};
+    use crate::arch::mapper::tests::banked_rom;
+    
+    fn bus(cart: Tigervision) -> Bus {
+        let mut bus = Bus::default();
+        bus.cart.set_mapper(Box::new(cart));
+        
+        bus
+    }
+    
+    #[test]
+    fn switch_3f() {
+        let mut bus = bus(Tigervision::new(&banked_rom(4, 2048)));
+        assert_eq!(bus.read(0x1000), 0);
+        assert_eq!(bus.read(0x1800), 3);
+        for addr in [0x0002, 0x003E, 0x003F] {
+            bus.write(addr, 2);
+            assert_eq!(bus.read(0x1000), 2);
+            bus.write(addr, 1);
+            assert_eq!(bus.read(0x1000), 1);
+        }
+        assert_eq!(bus.read(0x1800), 3);
+        bus.write(0x0080, 2); // RIOT RAM
+        assert_eq!(bus.read(0x1000), 1);
+    }
+    
+    #[test]
+    fn switch_3e() {
+        let mut bus = bus(Tigervision::new_3e(&banked_rom(4, 2048)));
+        bus.write(0x003F, 2);
+        assert_eq!(bus.read(0x1000), 2);
+        bus.write(0x003E, 1);
+        bus.write(0x1405, 0x99);
+        assert_eq!(bus.read(0x1005), 0x99);
+        assert_eq!(bus.read(0x1800), 3);
+        
+        // WSYNC and the other TIA registers don't switch banks on 3E
+        for addr in [0x0002, 0x0009, 0x001B, 0x003D] {
+            bus.write(addr, 0);
+            assert_eq!(bus.cart.segments()[0], Segment::new(0x000, 0x800, Bank::Ram(1)), "writing ${:02X} switched banks", addr);
+        }
+        
+        bus.write(0x003F, 1);
+        assert_eq!(bus.read(0x1005), 1);
+        bus.write(0x003E, 1);
+        assert_eq!(bus.read(0x1005), 0x99, "RAM bank was lost");
+    }
+}
\ No newline at end of file
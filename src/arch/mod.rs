@@ -1,48 +1,436 @@
 use crate::arch::cartridge::Cartridge;
 use crate::arch::cpu::Cpu;
 use crate::arch::pia::Pia;
+use crate::arch::state::{impl_savestate, impl_savestate_enum, Savestate, StateError, StateReader, StateWriter};
 use crate::arch::tia::Tia;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use crate::util::{fnv1a, Rng};
 
 pub mod tia;
 pub mod cpu;
 pub mod pia;
 pub mod cartridge;
+pub mod input;
+pub mod mapper;
+pub mod state;
 
 pub trait BusAccessable {
     fn write(&mut self, addr: u16, data: u8);
     fn read(&mut self, addr: u16) -> u8;
 }
 
+/// Device plugged into one of the controller ports.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Controller {
+    #[default]
+    Joystick,
+    Paddles,
+    Keyboard,
+    Driving,
+    None,
+}
+impl_savestate_enum!(Controller { Joystick, Paddles, Keyboard, Driving, None });
+impl Display for Controller {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Controller::Joystick => "JOYSTICK",
+            Controller::Paddles => "PADDLES",
+            Controller::Keyboard => "KEYBOARD",
+            Controller::Driving => "DRIVING",
+            Controller::None => "NONE",
+        })
+    }
+}
+impl FromStr for Controller {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "JOYSTICK" => Ok(Controller::Joystick),
+            "PADDLES" => Ok(Controller::Paddles),
+            "KEYBOARD" => Ok(Controller::Keyboard),
+            "DRIVING" => Ok(Controller::Driving),
+            "NONE" => Ok(Controller::None),
+            _ => Err(format!("unknown controller: {}", s)),
+        }
+    }
+}
+
+/// How the parts of the machine that real hardware leaves undefined are set up at power-on.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum PowerOn {
+    /// Randomized from a seed, so the same seed always powers on the same way.
+    Random(u64),
+    /// Everything set to zero, which is useful for testing.
+    #[default]
+    Zero,
+}
+impl Savestate for PowerOn {
+    fn save(&self, w: &mut StateWriter) {
+        match self {
+            PowerOn::Random(seed) => {
+                0u8.save(w);
+                seed.save(w);
+            },
+            PowerOn::Zero => 1u8.save(w),
+        }
+    }
+    
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let mut tag = 0u8;
+        tag.load(r)?;
+        *self = match tag {
+            0 => {
+                let mut seed = 0u64;
+                seed.load(r)?;
+                PowerOn::Random(seed)
+            },
+            1 => PowerOn::Zero,
+            _ => return Err(StateError::Invalid("PowerOn")),
+        };
+        
+        Ok(())
+    }
+}
+impl Display for PowerOn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PowerOn::Random(seed) => write!(f, "{}", seed),
+            PowerOn::Zero => f.write_str("zero"),
+        }
+    }
+}
+impl FromStr for PowerOn {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(PowerOn::Zero),
+            _ => s.parse().map(PowerOn::Random).map_err(|_| format!("expected a seed or \"zero\", found \"{}\"", s)),
+        }
+    }
+}
+
 #[derive(Clone, Default, Debug)]
 pub struct Bus {
     pub tia: Tia,
     pub cpu: Cpu,
     pub pia: Pia,
     pub cart: Cartridge,
+    /// Devices in the left and right controller ports.
+    pub controllers: [Controller; 2],
+    /// Last value placed on the data bus. Undriven data lines will float to this value.
+    pub data_bus: u8,
+    /// Drive the undriven TIA data lines randomly, instead of leaving them at the last data bus value.
+    pub tia_pins_random: bool,
+    /// How the machine was powered on.
+    power_on: PowerOn,
+    rng: Rng,
+    /// Number of frames started since power-on.
+    pub frames: usize,
+    /// Whether the frame that last ended was a lag frame, one in which the controllers weren't read.
+    pub lag: bool,
+    /// Number of lag frames since power-on.
+    pub lag_frames: usize,
+    /// Whether the controllers (INPT4/INPT5 or SWCHA) were read since the frame started.
+    pub input_polled: bool,
+}
+
+impl_savestate!(Bus { tia, cpu, pia, cart, controllers, data_bus, tia_pins_random, power_on, rng, frames, lag, lag_frames, input_polled });
+
+impl Bus {
+    pub fn power_on_mode(&self) -> PowerOn {
+        self.power_on
+    }
+    
+    /// Sets up the RAM, CPU registers, TIA and RIOT timer the way they'd be at power-on, which is
+    /// random on real hardware. This should be done right after the ROM is loaded, before the CPU
+    /// reads the reset vector. The random number generator used while running starts from the
+    /// same seed, so a run can be reproduced from just the seed and the input.
+    pub fn power_on(&mut self, mode: PowerOn) {
+        self.power_on = mode;
+        self.rng = Rng::new(match mode {
+            PowerOn::Random(seed) => seed,
+            PowerOn::Zero => 0,
+        });
+        
+        let mut random = || match mode {
+            PowerOn::Random(_) => self.rng.next_u8(),
+            PowerOn::Zero => 0,
+        };
+        self.cpu.power_on(&mut random);
+        self.pia.power_on(&mut random);
+        self.tia.power_on(&mut random);
+        self.data_bus = random();
+    }
+    
+    /// Saves the complete state of the machine. This can be done between any two color clocks,
+    /// including in the middle of an instruction.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.bytes(state::MAGIC);
+        state::VERSION.save(&mut w);
+        self.save(&mut w);
+        
+        w.into_bytes()
+    }
+    
+    /// Hash of the complete state of the machine (as saved by [`Bus::save_state`]), for noticing
+    /// when two runs that should be the same, like a movie and its playback, aren't. Hashes can
+    /// only be compared between builds with the same savestate [`VERSION`](state::VERSION).
+    pub fn state_hash(&self) -> u64 {
+        let mut w = StateWriter::new();
+        self.save(&mut w);
+        
+        fnv1a(&w.into_bytes())
+    }
+    
+    /// Restores a state made by [`Bus::save_state`]. The state has to be for the ROM that's
+    /// currently loaded. If it can't be loaded, the machine is left untouched.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(data);
+        if r.bytes(state::MAGIC.len()).ok() != Some(&state::MAGIC[..]) {
+            return Err(StateError::NotAState);
+        }
+        let mut version = 0u16;
+        version.load(&mut r)?;
+        if version != state::VERSION {
+            return Err(StateError::Version(version));
+        }
+        
+        let mut bus = self.clone();
+        bus.load(&mut r)?;
+        if r.remaining() != 0 {
+            return Err(StateError::Invalid("length"));
+        }
+        *self = bus;
+        
+        Ok(())
+    }
+    
+    /// Should be called whenever a new frame starts, to decide whether the previous one was a lag
+    /// frame. The time from power-on to the first frame doesn't count.
+    pub fn start_frame(&mut self) {
+        if self.frames > 0 {
+            self.lag = !self.input_polled;
+            if self.lag {
+                self.lag_frames += 1;
+            }
+        }
+        self.frames += 1;
+        self.input_polled = false;
+    }
+    
+    fn apply_cart_pokes(&mut self) {
+        while let Some((addr, data)) = self.cart.take_ram_poke() {
+            self.pia.write(0x0080 | addr as u16, data);
+        }
+    }
 }
 
 impl BusAccessable for Bus {
     fn write(&mut self, addr: u16, data: u8) {
+        let addr = addr & 0x1FFF; // the 6507 only has 13 address lines
+        self.data_bus = data;
+        
         match addr {
-            0x0000..=0x002C => self.tia.write(addr, data),
-            0x002D..=0x002F => (), // apparently some games will occationally write into the void?
-            0x0030..=0x003D => (), // valid, but also, read-only registers
-            0x003E..=0x007F => (), // apparently some games will occationally write into the void?
-            0x0080..=0x00FF | 0x0280..=0x0297 => self.pia.write(addr, data),
-            0x0100..=0x01FF => self.cpu.write(addr, data),
-            0xF000..=0xFFFF => self.cart.write(addr, data),
-            _ => panic!("Write attempt to invalid address {:#06X} ({:#04X})", addr, data),
+            _ if addr & 0x1000 != 0 => self.cart.write(addr, data), // A12 selects the cartridge
+            _ if addr & 0x0080 == 0 => self.tia.write(addr & 0x003F, data), // A7 low selects the TIA (A5-A0)
+            _ if addr & 0x0200 == 0 => self.pia.write(0x0080 | (addr & 0x007F), data), // A9 low selects RIOT RAM (this includes the stack page mirror at $0180-$01FF)
+            _ => self.pia.write(0x0280 | (addr & 0x001F), data), // A9 high selects RIOT I/O and timer (A4-A0)
         }
+        self.cart.snoop(addr, data, true);
+        self.apply_cart_pokes();
     }
 
     fn read(&mut self, addr: u16) -> u8 {
-        match addr {
-            0x0000..=0x002C => 0x00, // valid, but also, write-only registers
-            0x0030..=0x003D => self.tia.read(addr),
-            0x0080..=0x00FF | 0x0280..=0x0297 => self.pia.read(addr),
-            0x0100..=0x01FF => self.cpu.read(addr),
-            0xF000..=0xFFFF => self.cart.read(addr),
-            _ => panic!("Read attempt to invalid address {:#06X}", addr),
+        let addr = addr & 0x1FFF;
+        
+        let data = match addr {
+            _ if addr & 0x1000 != 0 => self.cart.read(addr),
+            _ if addr & 0x0080 == 0 => { // only A3-A0 are decoded for TIA reads
+                if addr & 0x000E == 0x000C { // INPT4 and INPT5 hold the fire buttons
+                    self.input_polled = true;
+                }
+                let tia = self.tia.read(0x0030 | (addr & 0x000F));
+                
+                // the TIA only drives D7 and D6, the rest of the lines keep whatever was last on the bus
+                let undriven = if self.tia_pins_random { self.rng.next_u8() } else { self.data_bus };
+                (tia & 0b11000000) | (undriven & 0b00111111)
+            },
+            _ if addr & 0x0200 == 0 => self.pia.read(0x0080 | (addr & 0x007F)),
+            _ => {
+                if addr & 0x0007 == 0 { // SWCHA holds the joystick directions
+                    self.input_polled = true;
+                }
+                self.pia.read(0x0280 | (addr & 0x001F))
+            },
+        };
+        self.data_bus = data;
+        self.cart.snoop(addr, data, false);
+        self.apply_cart_pokes();
+        
+        data
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::util::InfCell;
+    
+    /// Machine with a 4K cartridge that starts running `program` at $F000. The rest of the ROM is NOPs.
+    pub fn machine(program: &[u8]) -> InfCell<Bus> {
+        let mut rom = vec![0xEA; 4096];
+        rom[..program.len()].copy_from_slice(program);
+        rom[0xFFC] = 0x00;
+        rom[0xFFD] = 0xF0;
+        
+        let bus_cell = InfCell::new(Bus::default());
+        let bus = bus_cell.get_mut();
+        bus.cart.set_rom(&rom).unwrap();
+        bus.cpu.init_pc(bus_cell.get_mut());
+        
+        bus_cell
+    }
+    
+    #[test]
+    fn stack_is_riot_ram() {
+        // LDX #$FF; TXS; LDA #$42; PHA; LDA $FF; STA $80; LDA #$37; STA $FF; PLA; STA $81
+        let bus_cell = machine(&[0xA2, 0xFF, 0x9A, 0xA9, 0x42, 0x48, 0xA5, 0xFF, 0x85, 0x80, 0xA9, 0x37, 0x85, 0xFF, 0x68, 0x85, 0x81]);
+        let bus = bus_cell.get_mut();
+        for _ in 0..40 {
+            bus.cpu.cycle(&bus_cell);
+        }
+        
+        assert_eq!(bus.read(0x0080), 0x42, "zero page read doesn't see what PHA pushed");
+        assert_eq!(bus.read(0x0081), 0x37, "PLA doesn't see what was written to the zero page");
+        assert_eq!(bus.cpu.sp.0, 0xFF);
+        for addr in 0x0180..=0x01FF {
+            assert_eq!(bus.read(addr), bus.read(addr - 0x0100), "${:04X} isn't a mirror of ${:04X}", addr, addr - 0x0100);
+        }
+        bus.write(0x01C0, 0x99);
+        assert_eq!(bus.read(0x00C0), 0x99);
+    }
+    
+    #[test]
+    fn address_decoding() {
+        let mut rom = vec![0xEA; 4096];
+        rom[0x080] = 0x5A;
+        let mut bus = Bus::default();
+        bus.cart.set_rom(&rom).unwrap();
+        
+        // A12 selects the cartridge, and A15-A13 aren't connected
+        assert_eq!(bus.read(0x1080), 0x5A);
+        assert_eq!(bus.read(0xF080), 0x5A);
+        
+        // A7 low selects the TIA
+        bus.write(0x0040, 0x02); // VSYNC
+        assert!(bus.tia.in_vsync());
+        bus.write(0x0100, 0x00);
+        assert!(!bus.tia.in_vsync());
+        
+        // A9 low selects RIOT RAM
+        bus.write(0x0180, 0x37);
+        assert_eq!(bus.read(0x0080), 0x37);
+        
+        // A9 high selects RIOT I/O
+        bus.write(0x02A1, 0xFF); // SWACNT
+        bus.write(0x02A0, 0xA5); // SWCHA
+        assert_eq!(bus.read(0x0381), 0xFF);
+        assert_eq!(bus.read(0x0380), 0xA5);
+        assert_eq!(bus.read(0x0080), 0x37, "RIOT I/O writes reached RAM");
+    }
+    
+    #[test]
+    fn power_on() {
+        // boxed, since a few machines at once don't fit on the test thread's stack
+        let powered_on = |mode| {
+            let mut bus = Box::<Bus>::default();
+            bus.power_on(mode);
+            bus
+        };
+        assert!(powered_on(PowerOn::Random(7)).save_state() == powered_on(PowerOn::Random(7)).save_state());
+        assert!(powered_on(PowerOn::Random(7)).save_state() != powered_on(PowerOn::Random(8)).save_state());
+        
+        let mut bus = powered_on(PowerOn::Random(7));
+        assert!((0x0080..=0x00FF).any(|addr| bus.read(addr) != 0), "RAM isn't randomized");
+        let mut bus = powered_on(PowerOn::Zero);
+        assert!((0x0080..=0x00FF).all(|addr| bus.read(addr) == 0));
+        assert_eq!((bus.cpu.acc, bus.cpu.x, bus.cpu.y, bus.cpu.sp.0), (0, 0, 0, 0));
+        assert_eq!(bus.power_on_mode(), PowerOn::Zero);
+    }
+    
+    #[test]
+    fn undriven_tia_pins() {
+        let mut bus = Box::<Bus>::default();
+        bus.power_on(PowerOn::Zero);
+        bus.write(0x0080, 0xEA);
+        assert_eq!(bus.read(0x0030) & 0b00111111, 0x2A, "D5-D0 don't hold the last bus value");
+        bus.read(0x0081);
+        assert_eq!(bus.read(0x0030) & 0b00111111, 0x00);
+        
+        // with the pins driven randomly, the values only depend on the power-on seed
+        let undriven = |seed| {
+            let mut bus = Box::<Bus>::default();
+            bus.power_on(PowerOn::Random(seed));
+            bus.tia_pins_random = true;
+            (0..16).map(|_| {
+                bus.write(0x0080, 0xFF);
+                bus.read(0x0030) & 0b00111111
+            }).collect::<Vec<_>>()
+        };
+        assert_eq!(undriven(7), undriven(7));
+        assert_ne!(undriven(7), undriven(8));
+        assert!(undriven(7).iter().any(|&bits| bits != 0b00111111), "D5-D0 still hold the last bus value");
+    }
+    
+    #[test]
+    fn state_hash() {
+        let bus_cell = machine(&[0xE6, 0x80, 0x4C, 0x00, 0xF0]); // loop: INC $80; JMP loop
+        let bus = bus_cell.get_mut();
+        for _ in 0..1000 {
+            bus.cpu.cycle(&bus_cell);
+        }
+        let hash = bus.state_hash();
+        let state = bus.save_state();
+        assert_eq!(bus.state_hash(), hash);
+        
+        let value = bus.read(0x00FF);
+        bus.write(0x00FF, value ^ 1);
+        assert_ne!(bus.state_hash(), hash, "hash doesn't cover RAM");
+        bus.load_state(&state).unwrap();
+        assert_eq!(bus.state_hash(), hash);
+        bus.cpu.cycle(&bus_cell);
+        assert_ne!(bus.state_hash(), hash, "hash doesn't change as the machine runs");
+    }
+    
+    #[test]
+    fn savestate_round_trip() {
+        // loop: INC $80; LDA $80; STA COLUPF; STA PF1; STA WSYNC; JMP loop
+        let bus_cell = machine(&[0xE6, 0x80, 0xA5, 0x80, 0x85, 0x08, 0x85, 0x0E, 0x85, 0x02, 0x4C, 0x00, 0xF0]);
+        let bus = bus_cell.get_mut();
+        bus.tia_pins_random = true;
+        // an odd number of color clocks, so the state is saved in the middle of an instruction
+        for _ in 0..100001 {
+            bus.tia.cycle(&bus_cell);
+        }
+        let saved = bus.save_state();
+        for _ in 0..50000 {
+            bus.tia.cycle(&bus_cell);
+        }
+        let after = bus.save_state();
+        
+        bus.load_state(&saved).unwrap();
+        assert!(bus.save_state() == saved, "saving a loaded state gives different bytes");
+        for _ in 0..50000 {
+            bus.tia.cycle(&bus_cell);
         }
+        assert!(bus.save_state() == after, "running from a loaded state gives a different result");
+        
+        assert!(bus.load_state(&saved[..saved.len() - 1]).is_err());
+        assert!(bus.load_state(b"nope").is_err());
+        assert!(bus.save_state() == after, "a failed load changed the machine");
     }
 }
\ No newline at end of file
@@ -1,63 +1,136 @@
 use crate::arch::BusAccessable;
-use crate::{Bus, InfCell};
+use crate::arch::state::impl_savestate;
+use crate::arch::Bus;
+use crate::util::InfCell;
+
+/// Timer interrupt flag (bit 7 of INSTAT/TIMINT).
+pub const INSTAT_TIMER: u8 = 0b10000000;
+/// PA7 edge-detect interrupt flag (bit 6 of INSTAT/TIMINT).
+pub const INSTAT_PA7: u8 = 0b01000000;
 
 #[derive(Clone, Debug)]
 pub struct Pia {
     ram: [u8; 128],
     pub(crate) intim: u8,
     pub(crate) intim_interval: usize,
-    pub(crate) intim_interval_active: bool,
     pub(crate) intim_counter: usize,
-    pub(crate) intim_trigger: bool,
+    pub(crate) intim_underflowed: bool,
+    pub(crate) intim_wrapped: bool,
+    pub(crate) timer_irq_enabled: bool,
+    pub(crate) instat: u8,
+    pub(crate) pa7_positive_edge: bool,
+    pub(crate) pa7_irq_enabled: bool,
+    pub(crate) pa7_last: bool,
+    /// Port A input pins, as driven by the controllers.
     pub swcha: u8,
+    pub(crate) swcha_out: u8,
+    pub(crate) swacnt: u8,
+    /// Port B input pins, as driven by the console switches.
     pub swchb: u8,
+    pub(crate) swchb_out: u8,
+    pub(crate) swbcnt: u8,
 }
 impl Default for Pia {
     fn default() -> Self { Self {
         ram: [0u8; 128],
-        intim: 0x0A, // Is likely random at cold boot
+        intim: 0, // random at power-on (see `power_on`)
         intim_interval: 1024, // Stella seems? consistent on this to be 1024
-        intim_interval_active: true,
-        intim_counter: 1,
-        intim_trigger: false,
+        intim_counter: 1024,
+        intim_underflowed: false,
+        intim_wrapped: false,
+        timer_irq_enabled: false,
+        instat: 0,
+        pa7_positive_edge: false,
+        pa7_irq_enabled: false,
+        pa7_last: true,
         swcha: 0b11111111,
+        swcha_out: 0,
+        swacnt: 0,
         swchb: 0b00111111,
+        swchb_out: 0,
+        swbcnt: 0,
     }}
 }
+impl_savestate!(Pia { ram, intim, intim_interval, intim_counter, intim_underflowed, intim_wrapped, timer_irq_enabled, instat, pa7_positive_edge, pa7_irq_enabled, pa7_last, swcha, swcha_out, swacnt, swchb, swchb_out, swbcnt });
 impl Pia {
-    pub fn cycle(&mut self, bus_cell: &InfCell<Bus>) {
-        let bus = bus_cell.get_mut();
+    /// Clocks the interval timer by one CPU cycle.
+    /// 
+    /// INTIM is decremented once every `intim_interval` cycles. When it wraps from 0x00 to 0xFF,
+    /// the timer flag in INSTAT is set and the timer continues to decrement once per cycle
+    /// until a new value is written to one of the timer registers.
+    pub fn cycle(&mut self, _bus_cell: &InfCell<Bus>) {
+        self.detect_pa7_edge();
         
+        self.intim_wrapped = false;
         
-        if self.intim_trigger {
-            self.intim_counter = 1;
-            self.intim_interval_active = true;
-            self.intim_trigger = false;
-        } else {
-            if self.intim_interval_active {
-                self.intim_counter -= 1;
-                if self.intim_counter == 0 {
-                    self.intim = self.intim.wrapping_sub(1);
-                    if self.intim == 0xFF { // underflow occured
-                        self.intim_interval_active = false;
-                    }
-                    
-                    if self.intim_interval_active {
-                        self.intim_counter = self.intim_interval;
-                    } else {
-                        self.intim_counter = 1;
-                    }
-                }
+        self.intim_counter -= 1;
+        if self.intim_counter == 0 {
+            self.intim = self.intim.wrapping_sub(1);
+            if self.intim == 0xFF { // underflow occured
+                self.intim_underflowed = true;
+                self.intim_wrapped = true;
+                self.instat |= INSTAT_TIMER;
+            }
+            
+            if self.intim_underflowed {
+                self.intim_counter = 1;
             } else {
-                self.intim = self.intim.wrapping_sub(1);
+                self.intim_counter = self.intim_interval;
             }
         }
     }
     
+    /// Sets the RAM and timer, which are undefined at power-on. The timer keeps counting down
+    /// from wherever it started, in the default 1024 cycle interval.
+    pub fn power_on(&mut self, random: &mut impl FnMut() -> u8) {
+        self.ram.fill_with(&mut *random);
+        self.intim = random();
+        self.intim_counter = self.intim_interval - (u16::from_le_bytes([random(), random()]) as usize % self.intim_interval);
+    }
+    
+    /// Value seen on the port A pins. Pins configured as outputs can only pull an input low.
+    pub fn port_a(&self) -> u8 {
+        (self.swcha_out | !self.swacnt) & self.swcha
+    }
+    
+    /// Value seen on the port B pins. Pins configured as outputs read back the output register.
+    pub fn port_b(&self) -> u8 {
+        (self.swchb_out | !self.swbcnt) & (self.swchb | self.swbcnt)
+    }
+    
+    /// Sets the PA7 flag in INSTAT if PA7 changed in the direction selected by the edge-detect control.
+    fn detect_pa7_edge(&mut self) {
+        let pa7 = self.port_a() & 0b10000000 != 0;
+        if pa7 != self.pa7_last && pa7 == self.pa7_positive_edge {
+            self.instat |= INSTAT_PA7;
+        }
+        self.pa7_last = pa7;
+    }
+    
     fn setup_intim(&mut self, intim: u8, interval: usize) {
         self.intim = intim;
         self.intim_interval = interval;
-        self.intim_trigger = true;
+        self.intim_counter = 1; // the first decrement happens on the very next cycle
+        self.intim_underflowed = false;
+        self.intim_wrapped = false;
+        self.instat &= !INSTAT_TIMER;
+    }
+    
+    /// Reads INTIM. This clears the timer flag, unless the timer wrapped during this same cycle.
+    fn read_intim(&mut self) -> u8 {
+        if !self.intim_wrapped {
+            self.instat &= !INSTAT_TIMER;
+        }
+        
+        self.intim
+    }
+    
+    /// Reads INSTAT (also known as TIMINT). This clears the PA7 flag, but leaves the timer flag alone.
+    fn read_instat(&mut self) -> u8 {
+        let instat = self.instat;
+        self.instat &= !INSTAT_PA7;
+        
+        instat
     }
 }
 
@@ -66,31 +139,150 @@ impl BusAccessable for Pia {
         match addr {
             0x0080..=0x00FF => self.ram[(addr & 0x007F) as usize] = data,
             
-            0x0294 => self.setup_intim(data, 1),
-            0x0295 => self.setup_intim(data, 8),
-            0x0296 => self.setup_intim(data, 64),
-            0x0297 => self.setup_intim(data, 1024),
-            _ => panic!("Write attempt to invalid address {:#06X} ({:#04X})", addr, data),
+            // A2 = 0: I/O registers, A4 and A3 are ignored
+            _ if addr & 0b100 == 0 => {
+                match addr & 0b11 {
+                    0b00 => self.swcha_out = data, // SWCHA
+                    0b01 => self.swacnt = data,    // SWACNT
+                    0b10 => self.swchb_out = data, // SWCHB
+                    _    => self.swbcnt = data,    // SWBCNT
+                }
+                self.detect_pa7_edge();
+            },
+            
+            // A2 = 1, A4 = 1: write timer, A3 = timer interrupt enable, A1-A0 = interval
+            _ if addr & 0b10000 != 0 => {
+                self.timer_irq_enabled = addr & 0b1000 != 0;
+                match addr & 0b11 {
+                    0b00 => self.setup_intim(data, 1),    // TIM1T
+                    0b01 => self.setup_intim(data, 8),    // TIM8T
+                    0b10 => self.setup_intim(data, 64),   // TIM64T
+                    _    => self.setup_intim(data, 1024), // T1024T
+                }
+            },
+            
+            // A2 = 1, A4 = 0: edge-detect control, A1 = PA7 interrupt enable, A0 = positive edge
+            _ => {
+                self.pa7_irq_enabled = addr & 0b10 != 0;
+                self.pa7_positive_edge = addr & 0b1 != 0;
+            },
         }
     }
     fn read(&mut self, addr: u16) -> u8 {
         match addr {
             0x0080..=0x00FF => self.ram[(addr & 0x007F) as usize],
-            0x0280 => self.swcha, // SWCHA
-            0x0281 => unimplemented!(),
-            0x0282 => self.swchb, // SWCHB
-            0x0283 => unimplemented!(),
-            0x0284 => {
-                self.intim_interval_active = true;
-                self.intim
-            }, // INTIM
-            0x0285 => unimplemented!(), // INSTAT (need to find documentation for this)
             
-            0x0294 => unimplemented!(),
-            0x0295 => unimplemented!(),
-            0x0296 => unimplemented!(),
-            0x0297 => unimplemented!(),
-            _ => panic!("Read attempt to invalid address {:#06X}", addr),
+            // A2 = 0: I/O registers, A4 and A3 are ignored
+            _ if addr & 0b100 == 0 => match addr & 0b11 {
+                0b00 => self.port_a(), // SWCHA
+                0b01 => self.swacnt,   // SWACNT
+                0b10 => self.port_b(), // SWCHB
+                _    => self.swbcnt,   // SWBCNT
+            },
+            
+            // A2 = 1: A0 selects INTIM/INSTAT, A3 = timer interrupt enable, A4 and A1 are ignored
+            _ => {
+                self.timer_irq_enabled = addr & 0b1000 != 0;
+                if addr & 0b1 == 0 {
+                    self.read_intim() // INTIM
+                } else {
+                    self.read_instat() // INSTAT
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn intim_clears_timer_flag() {
+        let bus_cell = InfCell::new(Bus::default());
+        let mut pia = Pia::default();
+        pia.write(0x0294, 2); // TIM1T
+        for _ in 0..3 {
+            pia.cycle(&bus_cell);
         }
+        assert_eq!(pia.instat & INSTAT_TIMER, INSTAT_TIMER, "timer flag isn't set when INTIM wraps");
+        
+        // reading INTIM on the cycle the timer wraps doesn't clear the flag
+        assert_eq!(pia.read(0x0284), 0xFF);
+        assert_eq!(pia.instat & INSTAT_TIMER, INSTAT_TIMER);
+        pia.cycle(&bus_cell);
+        assert_eq!(pia.read(0x0284), 0xFE);
+        assert_eq!(pia.instat & INSTAT_TIMER, 0);
+    }
+    
+    #[test]
+    fn instat_clears_pa7_flag() {
+        let mut pia = Pia { instat: INSTAT_TIMER | INSTAT_PA7, ..Pia::default() };
+        assert_eq!(pia.read(0x0285), INSTAT_TIMER | INSTAT_PA7);
+        assert_eq!(pia.instat, INSTAT_TIMER);
+        assert_eq!(pia.read(0x0285), INSTAT_TIMER);
+    }
+    
+    #[test]
+    fn timer_mirrors() {
+        let mut bus = Bus::default();
+        bus.write(0x0396, 10); // TIM64T, mirrored with A8 set
+        assert_eq!((bus.pia.intim, bus.pia.intim_interval), (10, 64));
+        bus.write(0x069F, 20); // T1024T with the interrupt enabled, mirrored with A10 set
+        assert_eq!((bus.pia.intim, bus.pia.intim_interval), (20, 1024));
+        assert!(bus.pia.timer_irq_enabled);
+        
+        // A4 and A1 are ignored when reading INTIM and INSTAT
+        for addr in [0x0284, 0x0286, 0x0294, 0x0B96] {
+            assert_eq!(bus.read(addr), 20, "INTIM isn't readable at ${:04X}", addr);
+        }
+        bus.pia.instat = INSTAT_PA7;
+        assert_eq!(bus.read(0x0297), INSTAT_PA7);
+        assert_eq!(bus.pia.instat, 0);
+    }
+    
+    #[test]
+    fn pa7_edges() {
+        let bus_cell = InfCell::new(Bus::default());
+        let mut pia = Pia::default();
+        let set_pa7 = |pia: &mut Pia, high: bool| {
+            pia.swcha = if high { 0xFF } else { 0x7F };
+            pia.cycle(&bus_cell);
+            pia.read(0x0285) & INSTAT_PA7 != 0
+        };
+        
+        pia.write(0x0284, 0); // negative edge
+        assert!(set_pa7(&mut pia, false));
+        assert!(!set_pa7(&mut pia, true), "rising edge detected while looking for falling edges");
+        
+        pia.write(0x0287, 0); // positive edge, with the interrupt enabled
+        assert!(pia.pa7_positive_edge && pia.pa7_irq_enabled);
+        assert!(!set_pa7(&mut pia, false), "falling edge detected while looking for rising edges");
+        assert!(set_pa7(&mut pia, true));
+        
+        // driving PA7 low as an output counts as an edge too
+        pia.write(0x0284, 0);
+        pia.write(0x0281, 0x80); // SWACNT
+        pia.write(0x0280, 0x00); // SWCHA
+        assert_eq!(pia.read(0x0285) & INSTAT_PA7, INSTAT_PA7);
+    }
+    
+    #[test]
+    fn output_pins() {
+        let mut pia = Pia::default();
+        pia.write(0x0281, 0x0F); // SWACNT: low nibble is output
+        pia.write(0x0280, 0x05);
+        assert_eq!(pia.read(0x0280), 0xF5);
+        // outputs can only pull the pins low, and inputs read the pins
+        pia.swcha = 0x7E;
+        assert_eq!(pia.read(0x0280), 0x74);
+        assert_eq!(pia.read(0x0281), 0x0F);
+        
+        pia.write(0x0283, 0x0F); // SWBCNT: low nibble is output
+        pia.write(0x0282, 0x05);
+        pia.swchb = 0b00110000;
+        // outputs read back the output register, whatever the pins are
+        assert_eq!(pia.read(0x0282), 0x35);
+        assert_eq!(pia.read(0x0283), 0x0F);
     }
 }
\ No newline at end of file
@@ -0,0 +1,258 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::num::Wrapping;
+
+/// Identifies a savestate file.
+pub const MAGIC: &[u8; 8] = b"RA26SAVE";
+/// Version of the savestate format. Bump this whenever anything that gets saved changes, since
+/// states are plain sequences of fields without any tags.
+pub const VERSION: u16 = 6;
+
+#[derive(Debug)]
+pub enum StateError {
+    /// The data doesn't start with [`MAGIC`].
+    NotAState,
+    /// The state was made by a different version of the format.
+    Version(u16),
+    /// The state ended before everything was read.
+    Truncated,
+    /// The state doesn't fit what it's being loaded into.
+    Mismatch(&'static str),
+    /// The state holds a value that can't be loaded.
+    Invalid(&'static str),
+}
+impl Display for StateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a savestate"),
+            StateError::Version(version) => write!(f, "savestate is version {}, but only version {} is supported", version, VERSION),
+            StateError::Truncated => write!(f, "state is truncated"),
+            StateError::Mismatch(what) => write!(f, "state doesn't match the current {}", what),
+            StateError::Invalid(what) => write!(f, "state contains an invalid {}", what),
+        }
+    }
+}
+impl std::error::Error for StateError {}
+
+/// Builds up a binary state. All values are little-endian.
+#[derive(Clone, Debug, Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+    
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Reads back a state made by [`StateWriter`].
+#[derive(Clone, Debug)]
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+    
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if len > self.data.len() {
+            return Err(StateError::Truncated);
+        }
+        
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        
+        Ok(bytes)
+    }
+    
+    /// Number of bytes that haven't been read yet.
+    pub fn remaining(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// Something that can be written to and restored from a state.
+///
+/// Loading happens in place, so anything that isn't part of the state (like ROM contents or
+/// function pointers) is kept from the current value.
+pub trait Savestate {
+    fn save(&self, w: &mut StateWriter);
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+
+macro_rules! savestate_int {
+    ($($ty:ty),*) => {$(
+        impl Savestate for $ty {
+            fn save(&self, w: &mut StateWriter) {
+                w.bytes(&self.to_le_bytes());
+            }
+            fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+                *self = <$ty>::from_le_bytes(r.bytes(std::mem::size_of::<$ty>())?.try_into().unwrap());
+                Ok(())
+            }
+        }
+    )*};
+}
+savestate_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl Savestate for usize {
+    fn save(&self, w: &mut StateWriter) {
+        (*self as u64).save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let mut value = 0u64;
+        value.load(r)?;
+        *self = value as usize;
+        Ok(())
+    }
+}
+
+impl Savestate for bool {
+    fn save(&self, w: &mut StateWriter) {
+        (*self as u8).save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let mut value = 0u8;
+        value.load(r)?;
+        *self = value != 0;
+        Ok(())
+    }
+}
+
+impl<T: Savestate, const N: usize> Savestate for [T; N] {
+    fn save(&self, w: &mut StateWriter) {
+        for value in self {
+            value.save(w);
+        }
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for value in self {
+            value.load(r)?;
+        }
+        Ok(())
+    }
+}
+
+/// Vecs are saved with their length, and resized to it when loaded.
+impl<T: Savestate + Default + Clone> Savestate for Vec<T> {
+    fn save(&self, w: &mut StateWriter) {
+        self.len().save(w);
+        for value in self {
+            value.save(w);
+        }
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let mut len = 0usize;
+        len.load(r)?;
+        if len > r.remaining() {
+            return Err(StateError::Truncated);
+        }
+        
+        self.resize(len, T::default());
+        for value in self {
+            value.load(r)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Savestate + Default + Clone> Savestate for VecDeque<T> {
+    fn save(&self, w: &mut StateWriter) {
+        self.len().save(w);
+        for value in self {
+            value.save(w);
+        }
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let mut values: Vec<T> = vec![];
+        values.load(r)?;
+        *self = values.into();
+        Ok(())
+    }
+}
+
+impl<T: Savestate + Default> Savestate for Option<T> {
+    fn save(&self, w: &mut StateWriter) {
+        self.is_some().save(w);
+        if let Some(value) = self {
+            value.save(w);
+        }
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let mut present = false;
+        present.load(r)?;
+        
+        if present {
+            self.get_or_insert_with(T::default).load(r)
+        } else {
+            *self = None;
+            Ok(())
+        }
+    }
+}
+
+impl<T: Savestate> Savestate for Wrapping<T> {
+    fn save(&self, w: &mut StateWriter) {
+        self.0.save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.0.load(r)
+    }
+}
+
+impl<A: Savestate, B: Savestate> Savestate for (A, B) {
+    fn save(&self, w: &mut StateWriter) {
+        self.0.save(w);
+        self.1.save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.0.load(r)?;
+        self.1.load(r)
+    }
+}
+
+/// Implements [`Savestate`] for a struct by saving and loading the listed fields in order.
+macro_rules! impl_savestate {
+    ($ty:ty { $($field:ident),* $(,)? }) => {
+        impl $crate::arch::state::Savestate for $ty {
+            #[allow(unused_variables)]
+            fn save(&self, w: &mut $crate::arch::state::StateWriter) {
+                $( $crate::arch::state::Savestate::save(&self.$field, w); )*
+            }
+            #[allow(unused_variables)]
+            fn load(&mut self, r: &mut $crate::arch::state::StateReader) -> Result<(), $crate::arch::state::StateError> {
+                $( $crate::arch::state::Savestate::load(&mut self.$field, r)?; )*
+                Ok(())
+            }
+        }
+    };
+}
+pub(crate) use impl_savestate;
+
+/// Implements [`Savestate`] for a fieldless enum, saved as the index of the variant in the list.
+macro_rules! impl_savestate_enum {
+    ($ty:ident { $($variant:ident),* $(,)? }) => {
+        impl $crate::arch::state::Savestate for $ty {
+            fn save(&self, w: &mut $crate::arch::state::StateWriter) {
+                let index = [$($ty::$variant),*].iter().position(|variant| variant == self).unwrap() as u8;
+                $crate::arch::state::Savestate::save(&index, w);
+            }
+            fn load(&mut self, r: &mut $crate::arch::state::StateReader) -> Result<(), $crate::arch::state::StateError> {
+                let mut index = 0u8;
+                $crate::arch::state::Savestate::load(&mut index, r)?;
+                *self = *[$($ty::$variant),*].get(index as usize).ok_or($crate::arch::state::StateError::Invalid(stringify!($ty)))?;
+                Ok(())
+            }
+        }
+    };
+}
+pub(crate) use impl_savestate_enum;
\ No newline at end of file
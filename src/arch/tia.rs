@@ -1,5 +1,9 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 use crate::arch::BusAccessable;
-use crate::{Bus, Cpu, InfCell};
+use crate::arch::state::{impl_savestate, impl_savestate_enum};
+use crate::arch::Bus;
+use crate::util::InfCell;
 
 pub const NTSC_COLOR_LUT: [u32; 128] = [
     0x000000, 0x404040, 0x6C6C6C, 0x909090, 0xB0B0B0, 0xC8C8C8, 0xDCDCDC, 0xECECEC,//
@@ -27,6 +31,37 @@ pub const SECAM_COLOR_LUT: [u32; 8] = [
     0x000000, 0x2121FF, 0xF03C79, 0xFF50FF, 0x7FFF00, 0x7FFFFF, 0xFFFF3F, 0xFFFFFF
 ];
 
+/// TV standard the console was made for, which decides the color palette.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Secam,
+}
+impl_savestate_enum!(Region { Ntsc, Pal, Secam });
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Region::Ntsc => "NTSC",
+            Region::Pal => "PAL",
+            Region::Secam => "SECAM",
+        })
+    }
+}
+impl FromStr for Region {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "NTSC" => Ok(Region::Ntsc),
+            "PAL" => Ok(Region::Pal),
+            "SECAM" => Ok(Region::Secam),
+            _ => Err(format!("unknown region: {}", s)),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Default)]
 pub struct CycleCounter {
     pub(crate) osc: usize,
@@ -36,6 +71,7 @@ pub struct CycleCounter {
     pub(crate) frame_cpu_counter: usize,
     pub(crate) frame_counter: usize,
 }
+impl_savestate!(CycleCounter { osc, div3, scanline, color_clock, frame_cpu_counter, frame_counter });
 impl CycleCounter {
     fn osc_cycle(&mut self) {
         self.osc += 1;
@@ -77,9 +113,16 @@ pub struct Tia {
     pf1: u8,
     pf2: u8,
     
+    audc: [u8; 2],
+    audf: [u8; 2],
+    audv: [u8; 2],
+    
     pub cycles: CycleCounter,
     pub framebuffer: [u32; 228 * 262],
     pub fb_color: u32,
+    pub region: Region,
+    /// Whether the fire buttons of the left and right joysticks are held.
+    pub fire: [bool; 2],
 }
 impl Default for Tia {
     fn default() -> Self { Self {
@@ -97,12 +140,39 @@ impl Default for Tia {
         pf1: 0,
         pf2: 0,
         
+        audc: [0; 2],
+        audf: [0; 2],
+        audv: [0; 2],
+        
         cycles: Default::default(),
         framebuffer: [0u32; 228 * 262],
         fb_color: 0,
+        region: Region::Ntsc,
+        fire: [false; 2],
     }}
 }
+impl_savestate!(Tia { vsync, vsync_trigger, vblank, wsync, colupf, colubk, ctrlpf, pf0, pf1, pf2, audc, audf, audv, cycles, framebuffer, fb_color, region, fire });
 impl Tia {
+    /// Sets the registers and beam position, which are undefined at power-on. The object position
+    /// counters, which are also random on real hardware, aren't emulated yet.
+    pub fn power_on(&mut self, random: &mut impl FnMut() -> u8) {
+        self.colupf = random();
+        self.colubk = random();
+        self.ctrlpf = random();
+        self.pf0 = random();
+        self.pf1 = random();
+        self.pf2 = random();
+        
+        self.cycles.color_clock = random() as usize % 228;
+        self.cycles.scanline = u16::from_le_bytes([random(), random()]) as usize % 262;
+        self.cycles.div3 = random() % 3;
+    }
+    
+    /// Whether VSYNC is currently on. A new frame starts when it's turned on.
+    pub fn in_vsync(&self) -> bool {
+        self.vsync
+    }
+    
     /// Perform one clock cycle of the TIA chip. This chip contains a clock divider which
     /// drives the CPU's PHI0 clock input. This function should not be called from within
     /// the CPU.
@@ -133,7 +203,7 @@ impl Tia {
         
         //self.debug_color_clock();
         
-        cpu.rdy = !self.wsync;
+        cpu.rdy = !self.wsync && !bus.cart.stalling();
         
         if self.cycles.div3 == 0 {
             // === Phi 0 CLOCK === //
@@ -144,11 +214,12 @@ impl Tia {
             
             // === Phi 2 CLOCK === //
             pia.cycle(bus_cell);
+            bus.cart.cycle();
         }
         
         self.debug_playfield();
         
-        //println!("FRAME: {}, SCANLINE: {}, HORIZ: {}, INTIM: {:02X}, INTIM_COUNTER: {:04X}, INTERVAL: {} ({})", self.cycles.frame_counter, self.cycles.scanline, self.cycles.color_clock, bus.pia.intim, bus.pia.intim_counter, bus.pia.intim_interval, bus.pia.intim_interval_active);
+        //println!("FRAME: {}, SCANLINE: {}, HORIZ: {}, INTIM: {:02X}, INTIM_COUNTER: {:04X}, INTERVAL: {} ({})", self.cycles.frame_counter, self.cycles.scanline, self.cycles.color_clock, bus.pia.intim, bus.pia.intim_counter, bus.pia.intim_interval, bus.pia.intim_underflowed);
         self.cycles.osc_cycle();
         if self.cycles.color_clock == 0 {
             self.wsync = false;
@@ -266,7 +337,10 @@ impl Tia {
             0
         }*/
         
-        NTSC_COLOR_LUT[(colu / 2) as usize]
+        match self.region {
+            Region::Secam => SECAM_COLOR_LUT[((colu / 2) & 0b111) as usize],
+            _ => NTSC_COLOR_LUT[(colu / 2) as usize], //TODO: PAL palette
+        }
     }
 }
 impl BusAccessable for Tia {
@@ -298,17 +372,17 @@ impl BusAccessable for Tia {
             0x0D => self.pf0 = data & 0b11110000,
             0x0E => self.pf1 = data,
             0x0F => self.pf2 = data,
+            0x15 => self.audc[0] = data & 0b00001111,
+            0x16 => self.audc[1] = data & 0b00001111,
+            0x17 => self.audf[0] = data & 0b00011111,
+            0x18 => self.audf[1] = data & 0b00011111,
+            0x19 => self.audv[0] = data & 0b00001111,
+            0x1A => self.audv[1] = data & 0b00001111,
             /*0x10 => unimplemented!(),
             0x11 => unimplemented!(),
             0x12 => unimplemented!(),
             0x13 => unimplemented!(),
             0x14 => unimplemented!(),
-            0x15 => unimplemented!(),
-            0x16 => unimplemented!(),
-            0x17 => unimplemented!(),
-            0x18 => unimplemented!(),
-            0x19 => unimplemented!(),
-            0x1A => unimplemented!(),
             0x1B => unimplemented!(),
             0x1C => unimplemented!(),
             0x1D => unimplemented!(),
@@ -333,23 +407,25 @@ impl BusAccessable for Tia {
         }
     }
 
+    /// Only D7 and D6 of the returned value are driven by the TIA, the remaining bits are
+    /// supplied by the bus.
     fn read(&mut self, addr: u16) -> u8 {
         //println!("TIA Read from {:04X}", addr);
         match addr {
-            0x30 => unimplemented!(), // CXM0P
-            0x31 => unimplemented!(), // CXM1P
+            0x30 => 0b00000000, // CXM0P
+            0x31 => 0b00000000, // CXM1P
             0x32 => 0b00000000, // CXP0FB
             0x33 => 0b00000000, // CXP1FB
             0x34 => 0b00000000, // CXM0FB
             0x35 => 0b00000000, // CXM1FB
             0x36 => 0b00000000, // CXBLPF
             0x37 => 0b00000000, // CXPPMM
-            0x38 => unimplemented!(),
-            0x39 => unimplemented!(),
-            0x3A => unimplemented!(),
-            0x3B => unimplemented!(),
-            0x3C => 0b10000000, // INPT4 //TODO: Besides normal input handling, it appears this register has other functionality
-            0x3D => 0b10000000, // INPT5 //TODO: Besides normal input handling, it appears this register has other functionality
+            0x38 => 0b00000000, // INPT0 //TODO: paddles
+            0x39 => 0b00000000, // INPT1
+            0x3A => 0b00000000, // INPT2
+            0x3B => 0b00000000, // INPT3
+            0x3C => if self.fire[0] { 0b00000000 } else { 0b10000000 }, // INPT4 //TODO: Besides normal input handling, it appears this register has other functionality
+            0x3D => if self.fire[1] { 0b00000000 } else { 0b10000000 }, // INPT5 //TODO: Besides normal input handling, it appears this register has other functionality
             _ => 0//panic!("TIA: Invalid read from {:04X}", addr)
         }
     }
@@ -0,0 +1,306 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use crate::arch::{Bus, Controller, PowerOn};
+use crate::arch::input::{Input, Joystick};
+use crate::movie::{Frame, Movie};
+
+/// Core that BizHawk uses for the 2600, which is the one this importer was written against.
+const CORE: &str = "Atari2600Hawk";
+
+/// Buttons written to exported input logs, in groups, with the letter used for each.
+const LOG_KEY: [&[(&str, char)]; 3] = [
+    &[("Reset", 'r'), ("Select", 's')],
+    &[("P1 Up", 'U'), ("P1 Down", 'D'), ("P1 Left", 'L'), ("P1 Right", 'R'), ("P1 Button", 'B')],
+    &[("P2 Up", 'U'), ("P2 Down", 'D'), ("P2 Left", 'L'), ("P2 Right", 'R'), ("P2 Button", 'B')],
+];
+
+/// Converts a BizHawk movie (`.bk2`) into a native movie, for the machine that's set up to play
+/// it. `rom` is the loaded ROM image, which is checked against the movie's SHA1.
+///
+/// Along with the movie, returns warnings about anything that couldn't be carried over exactly,
+/// such as buttons without an equivalent here. Each line of the input log becomes one frame, which
+/// matches BizHawk as long as it also starts frames on VSYNC and logs lag frames like any other.
+/// The movie powers on with everything zeroed and the default console switches, like BizHawk.
+pub fn import<P: AsRef<Path>>(path: P, bus: &Bus, rom: &[u8]) -> Result<(Movie, Vec<String>), String> {
+    let path = path.as_ref();
+    let mut archive = ZipArchive::new(File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?)
+        .map_err(|err| format!("{}: bad zip archive: {}", path.display(), err))?;
+    let mut read = |name: &str| -> Result<String, String> {
+        let mut text = String::new();
+        archive.by_name(name)
+            .map_err(|err| format!("{}: {}: {}", path.display(), name, err))?
+            .read_to_string(&mut text)
+            .map_err(|err| format!("{}: {}: {}", path.display(), name, err))?;
+        
+        Ok(text)
+    };
+    
+    let mut warnings = vec![];
+    let header_text = read("Header.txt")?;
+    let header: BTreeMap<&str, &str> = header_text.lines().filter_map(|line| line.trim().split_once(' ')).collect();
+    check_header(&header, rom, &mut warnings)?;
+    
+    let mut movie = Movie::new(bus);
+    movie.power_on = PowerOn::Zero;
+    movie.switches = 0b00111111;
+    movie.frames = parse_input_log(&read("Input Log.txt")?, &mut warnings)?;
+    
+    // cores that don't end every frame on a VBlank count them separately
+    if let Some(vblanks) = header.get("VBlankCount") {
+        match vblanks.parse::<usize>() {
+            Ok(vblanks) if vblanks != movie.frames.len() => warnings.push(format!(
+                "header counts {} VBlanks, but the input log has {} frames. Not all of BizHawk's frames started on a VSYNC, so they won't line up",
+                vblanks, movie.frames.len())),
+            Ok(_) => (),
+            Err(_) => warnings.push(format!("header has an invalid VBlankCount: \"{}\"", vblanks)),
+        }
+    }
+    
+    if movie.frames.first().is_some_and(|frame| frame.input != Input::default()) {
+        warnings.push("input is held on the first frame. BizHawk applies it from power-on, but here it only takes effect at the first VSYNC".to_owned());
+    }
+    if read("LagLog").is_ok() {
+        warnings.push("the lag log (from a TAStudio project) was ignored, every line of input is treated as one frame".to_owned());
+    }
+    
+    Ok((movie, warnings))
+}
+
+fn check_header(header: &BTreeMap<&str, &str>, rom: &[u8], warnings: &mut Vec<String>) -> Result<(), String> {
+    match header.get("Platform") {
+        Some(&"A26") => (),
+        Some(platform) => return Err(format!("movie is for platform {}, not the Atari 2600 (A26)", platform)),
+        None => warnings.push("header doesn't say which platform the movie is for".to_owned()),
+    }
+    for key in ["StartsFromSavestate", "StartsFromSaveRam"] {
+        if header.get(key).is_some_and(|value| value.eq_ignore_ascii_case("true")) {
+            return Err(format!("movie doesn't start from power-on ({}), which can't be imported", key));
+        }
+    }
+    if let Some(core) = header.get("Core").filter(|core| **core != CORE) {
+        warnings.push(format!("movie was recorded with the {} core instead of {}, frames may not line up", core, CORE));
+    }
+    match header.get("SHA1") {
+        Some(sha1) if !sha1.eq_ignore_ascii_case(&sha1_smol::Sha1::from(rom).digest().to_string()) => {
+            warnings.push(format!("movie was recorded with a different ROM (SHA1 {})", sha1));
+        },
+        Some(_) => (),
+        None => warnings.push("header has no SHA1, so the ROM can't be checked".to_owned()),
+    }
+    
+    Ok(())
+}
+
+/// Reads the frames from a BizHawk input log. The buttons in each frame are laid out according to
+/// the log's `LogKey`, so logs from other versions still work as long as the button names match.
+fn parse_input_log(text: &str, warnings: &mut Vec<String>) -> Result<Vec<Frame>, String> {
+    let log_key = text.lines()
+        .find_map(|line| line.trim().strip_prefix("LogKey:"))
+        .ok_or_else(|| "input log has no LogKey".to_owned())?;
+    let groups: Vec<Vec<&str>> = log_key.split('#')
+        .filter(|group| !group.is_empty())
+        .map(|group| group.split('|').filter(|name| !name.is_empty()).collect())
+        .collect();
+    
+    let mut frames = vec![];
+    // buttons that can't be imported, with how many frames they're held on and the first of those
+    let mut ignored: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if !line.starts_with('|') {
+            continue;
+        }
+        
+        let fields: Vec<&str> = line.split('|').collect();
+        if fields.len() != groups.len() + 2 || groups.iter().zip(&fields[1..]).any(|(names, field)| names.len() != field.chars().count()) {
+            return Err(format!("input log line {}: \"{}\" doesn't match the LogKey", i + 1, line));
+        }
+        
+        let mut input = Input::default();
+        for (names, field) in groups.iter().zip(&fields[1..]) {
+            for (name, c) in names.iter().zip(field.chars()) {
+                if c == '.' || c == ' ' {
+                    continue;
+                }
+                if !press(&mut input, name) {
+                    ignored.entry(name).or_insert((0, frames.len())).0 += 1;
+                }
+            }
+        }
+        frames.push(Frame { input, ..Frame::default() });
+    }
+    
+    for (name, (count, first)) in ignored {
+        warnings.push(format!("{} is held on {} frames (first on frame {}), but has no equivalent here and was ignored", name, count, first));
+    }
+    
+    Ok(frames)
+}
+
+/// Presses the button with the given BizHawk name. Returns false for buttons that don't exist here.
+fn press(input: &mut Input, name: &str) -> bool {
+    match name {
+        "Reset" => input.reset = true,
+        "Select" => input.select = true,
+        _ => {
+            let (port, button) = match name.split_once(' ') {
+                Some(("P1", button)) => (0, button),
+                Some(("P2", button)) => (1, button),
+                _ => return false,
+            };
+            input.joysticks[port] |= match button {
+                "Up" => Joystick::UP,
+                "Down" => Joystick::DOWN,
+                "Left" => Joystick::LEFT,
+                "Right" => Joystick::RIGHT,
+                "Button" => Joystick::FIRE,
+                _ => return false,
+            };
+        },
+    }
+    
+    true
+}
+
+/// Whether the button with the given BizHawk name is held.
+fn held(input: &Input, name: &str) -> bool {
+    let mut pressed = Input::default();
+    press(&mut pressed, name)
+        && input.joysticks[0].contains(pressed.joysticks[0])
+        && input.joysticks[1].contains(pressed.joysticks[1])
+        && (input.reset || !pressed.reset)
+        && (input.select || !pressed.select)
+}
+
+/// Writes a movie as a BizHawk movie (`.bk2`). `rom` is the ROM image the movie is for, and `name`
+/// the game's name to put in the header.
+///
+/// Returns warnings about anything that BizHawk won't reproduce. Its own power-on state is used,
+/// so the movie's power-on seed and switches are lost.
+pub fn export<P: AsRef<Path>>(movie: &Movie, path: P, rom: &[u8], name: &str) -> Result<Vec<String>, String> {
+    let path = path.as_ref();
+    let mut warnings = vec![];
+    if movie.power_on != PowerOn::Zero {
+        warnings.push(format!("power-on seed {} can't be exported, BizHawk uses its own power-on state", movie.power_on));
+    }
+    if movie.switches != 0b00111111 {
+        warnings.push(format!("starting console switches ({:02X}) can't be exported", movie.switches));
+    }
+    if movie.controllers != [Controller::Joystick; 2] {
+        warnings.push(format!("only joysticks can be exported, not {} and {} controllers", movie.controllers[0], movie.controllers[1]));
+    }
+    if movie.frames.first().is_some_and(|frame| frame.input != Input::default()) {
+        warnings.push("input is held on the first frame. BizHawk applies it from power-on, not from the first VSYNC".to_owned());
+    }
+    
+    let mut header = String::new();
+    header.push_str("MovieVersion BizHawk v2.0.0\n");
+    header.push_str("Platform A26\n");
+    header.push_str(&format!("Core {}\n", CORE));
+    header.push_str(&format!("GameName {}\n", name));
+    header.push_str(&format!("SHA1 {}\n", sha1_smol::Sha1::from(rom).digest().to_string().to_ascii_uppercase()));
+    
+    let mut log = String::from("[Input]\nLogKey:");
+    for group in LOG_KEY {
+        log.push('#');
+        for (name, _) in group {
+            log.push_str(name);
+            log.push('|');
+        }
+    }
+    log.push('\n');
+    for Frame { input, .. } in &movie.frames {
+        log.push('|');
+        for group in LOG_KEY {
+            for (name, letter) in group {
+                log.push(if held(input, name) { *letter } else { '.' });
+            }
+            log.push('|');
+        }
+        log.push('\n');
+    }
+    log.push_str("[/Input]\n");
+    
+    let file = File::create(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let mut zip = ZipWriter::new(file);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, text) in [("Header.txt", &header), ("Input Log.txt", &log)] {
+        zip.start_file(name, options).map_err(|err| format!("{}: {}", path.display(), err))?;
+        zip.write_all(text.as_bytes()).map_err(|err| format!("{}: {}", path.display(), err))?;
+    }
+    zip.finish().map_err(|err| format!("{}: {}", path.display(), err))?;
+    
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn log_key() {
+        // buttons are laid out by the LogKey, not by a fixed order, and unknown ones are reported
+        let log = "[Input]\nLogKey:#P2 Button|P1 Up|#P1 Left|Power|Reset|\n|B.|.P.|\n|.U|L.r|\n|..|L..|\n[/Input]\n";
+        let mut warnings = vec![];
+        let frames = parse_input_log(log, &mut warnings).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].input.joysticks, [Joystick::empty(), Joystick::FIRE]);
+        assert_eq!(frames[1].input, Input { joysticks: [Joystick::UP | Joystick::LEFT, Joystick::empty()], reset: true, select: false });
+        assert_eq!(frames[2].input.joysticks[0], Joystick::LEFT);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Power is held on 1 frames (first on frame 0)"), "{}", warnings[0]);
+        
+        assert!(parse_input_log("|..|\n", &mut vec![]).is_err());
+        assert!(parse_input_log("LogKey:#P1 Up|P1 Down|\n|U|\n", &mut vec![]).is_err());
+    }
+    
+    #[test]
+    fn header() {
+        let rom = [0u8; 4096];
+        let sha1 = sha1_smol::Sha1::from(rom).digest().to_string();
+        let header = |text: &'static str| text.lines().filter_map(|line| line.split_once(' ')).collect::<BTreeMap<_, _>>();
+        
+        let mut warnings = vec![];
+        check_header(&header("Platform A26\nCore Atari2600Hawk"), &rom, &mut warnings).unwrap();
+        assert_eq!(warnings, ["header has no SHA1, so the ROM can't be checked"]);
+        
+        let mut warnings = vec![];
+        let mut fields = header("Platform A26\nCore Atari2600Hawk");
+        fields.insert("SHA1", &sha1);
+        check_header(&fields, &rom, &mut warnings).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        
+        assert!(check_header(&header("Platform NES"), &rom, &mut vec![]).is_err());
+        assert!(check_header(&header("Platform A26\nStartsFromSavestate True"), &rom, &mut vec![]).is_err());
+    }
+    
+    #[test]
+    fn round_trip() {
+        let mut rom = vec![0xEA; 4096];
+        rom[0xFFD] = 0xF0;
+        let mut bus = Bus::default();
+        bus.cart.set_rom(&rom).unwrap();
+        bus.power_on(PowerOn::Random(1));
+        bus.pia.swchb = 0b00110111;
+        
+        let mut movie = Movie::new(&bus);
+        movie.frames = (0..40).map(|i| Frame {
+            input: Input { joysticks: [Joystick::from_bits_truncate(i), Joystick::from_bits_truncate(i / 2)], reset: i % 4 == 1, select: i % 8 == 2 },
+            ..Frame::default()
+        }).collect();
+        let path = std::env::temp_dir().join(format!("rustari2600-test-{}.bk2", std::process::id()));
+        let warnings = export(&movie, &path, &rom, "test").unwrap();
+        assert_eq!(warnings.len(), 2, "{:?}", warnings); // the seed and the switches
+        
+        let (imported, warnings) = import(&path, &bus, &rom).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(warnings.is_empty(), "{:?}", warnings);
+        assert_eq!(imported.frames, movie.frames);
+        assert_eq!(imported.power_on, PowerOn::Zero);
+        assert_eq!(imported.switches, 0b00111111);
+    }
+}
\ No newline at end of file
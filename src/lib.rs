@@ -0,0 +1,10 @@
+pub mod arch;
+pub mod bk2;
+pub mod movie;
+pub mod properties;
+pub mod rewind;
+pub mod rom;
+pub mod run;
+pub mod slots;
+pub mod tas;
+pub mod util;
\ No newline at end of file
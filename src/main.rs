@@ -1,28 +1,246 @@
 use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use clap::{App, AppSettings, Arg};
 use minifb::{Key, KeyRepeat, Scale, ScaleMode, Window, WindowOptions};
-use crate::arch::Bus;
-use crate::arch::cpu::Cpu;
-use crate::util::InfCell;
-
-mod arch;
-mod util;
+use rustari2600::{bk2, properties, rom, run, slots};
+use rustari2600::arch::{Bus, PowerOn};
+use rustari2600::arch::input::{Input, Joystick};
+use rustari2600::arch::mapper::detect::MapperKind;
+use rustari2600::arch::mapper::starpath::Supercharger;
+use rustari2600::movie::{Mode, Movie, MoviePlayer};
+use rustari2600::properties::{Difficulty, PropertiesDb};
+use rustari2600::rewind::Rewind;
+use rustari2600::run::{RunState, Step};
+use rustari2600::slots::Slots;
+use rustari2600::util::InfCell;
 
 const DEBUG_UPDATE_PER_PIXEL: bool = false;
 const DEBUG_UPDATE_PER_FRAME: bool = true;
 
+/// Most frames that skipping lag frames goes through at once, in case the game never reads the
+/// controllers.
+const MAX_LAG_SKIP: usize = 600;
+
+/// Keys that select savestate slots 0-9.
+const SLOT_KEYS: [Key; 10] = [Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9];
+
 fn main() {
     let matches = App::new("Rustari2600")
         .arg(Arg::new("rom")
             .required(true)
-            .takes_value(true))
+            .takes_value(true)
+            .help("ROM image to run (.a26/.bin/.rom, or a .zip or .gz archive holding one)"))
+        .arg(Arg::new("rom-select")
+            .long("rom-select")
+            .takes_value(true)
+            .help("Name or index of the ROM image to run, when the zip archive holds more than one"))
+        .arg(Arg::new("tia-random-pins")
+            .long("tia-random-pins")
+            .help("Randomly drive the undriven data lines on TIA reads, instead of keeping the last data bus value"))
+        .arg(Arg::new("mapper")
+            .long("mapper")
+            .takes_value(true)
+            .help("Bankswitching scheme to use instead of detecting it (e.g. F8, F6SC, E0, 3F, DPC+, CDFJ, AR)"))
+        .arg(Arg::new("ar-loader")
+            .long("ar-loader")
+            .takes_value(true)
+            .help("2K fast loader to use in place of the BIOS for Supercharger (AR) tape images, instead of the built-in one. It has to load the same way (reading $F850); dumps of the real BIOS won't work, since tape audio isn't emulated"))
+        .arg(Arg::new("properties")
+            .long("properties")
+            .takes_value(true)
+            .help("Game properties file (stella.pro format) with entries that override the built-in ones"))
+        .arg(Arg::new("cart-ram")
+            .long("cart-ram")
+            .takes_value(true)
+            .help("File to keep the cartridge's RAM in between sessions. Loaded at startup if it exists, and saved on exit"))
+        .arg(Arg::new("load-state")
+            .long("load-state")
+            .takes_value(true)
+            .help("Savestate to load at startup. States are saved with F5 and loaded with F8, into the slot selected with 0-9"))
+        .arg(Arg::new("seed")
+            .long("seed")
+            .takes_value(true)
+            .help("Seed for the random power-on state, or \"zero\" to power on with everything zeroed. Picked at random if not given"))
+        .arg(Arg::new("record")
+            .long("record")
+            .takes_value(true)
+            .conflicts_with_all(&["play", "load-state"])
+            .help("Record a movie of the input from power-on, saved to this file on exit (as a BizHawk movie if it ends in .bk2)"))
+        .arg(Arg::new("play")
+            .long("play")
+            .takes_value(true)
+            .conflicts_with("load-state")
+            .help("Play back a movie (or a BizHawk .bk2 movie), read-only until T is pressed. In read-write mode, pressing anything takes over recording, and the movie is saved on exit"))
+        .arg(Arg::new("movie-hashes")
+            .long("movie-hashes")
+            .help("Save a hash of the machine's state with every recorded frame, so playback can report the first frame that desyncs"))
+        .arg(Arg::new("export-bk2")
+            .long("export-bk2")
+            .takes_value(true)
+            .help("Also save the recorded or played back movie to this file as a BizHawk movie on exit"))
+        .arg(Arg::new("rewind-interval")
+            .long("rewind-interval")
+            .takes_value(true)
+            .default_value("1")
+            .help("Number of frames between rewind snapshots. Hold Backspace to rewind"))
+        .arg(Arg::new("rewind-buffer")
+            .long("rewind-buffer")
+            .takes_value(true)
+            .default_value("32")
+            .help("Memory to keep rewind history in, in MiB"))
         .setting(AppSettings::NextLineHelp)
         .setting(AppSettings::ArgRequiredElseHelp)
         .setting(AppSettings::DeriveDisplayOrder)
         .get_matches();
     
-    let mut window = Window::new("Rustari2600", 228 * 3 / 2, 262, WindowOptions {
+    let bus_cell = InfCell::new(Bus::default());
+    let bus = bus_cell.get_mut();
+    let bus_ref = bus_cell.get_mut();
+    
+    bus.tia_pins_random = matches.is_present("tia-random-pins");
+    let (rom_name, rom) = match rom::load(PathBuf::from(matches.value_of("rom").unwrap()), matches.value_of("rom-select")) {
+        Ok(rom) => {
+            println!("Loaded {}", rom.name);
+            (rom.name, rom.data)
+        },
+        Err(err) => {
+            eprintln!("Failed to read ROM: {}", err);
+            std::process::exit(1);
+        },
+    };
+    
+    let mut db = PropertiesDb::builtin();
+    if let Some(path) = matches.value_of("properties") {
+        if let Err(err) = db.load_file(path) {
+            eprintln!("Failed to load properties: {}", err);
+            std::process::exit(1);
+        }
+    }
+    let md5 = properties::md5(&rom);
+    let props = db.get(&md5).cloned().unwrap_or_default();
+    if let Some(name) = &props.name {
+        println!("Found properties for {} ({})", name, md5);
+    }
+    
+    let loaded = match (matches.value_of("mapper"), props.mapper) {
+        (Some(name), _) => name.parse::<MapperKind>().and_then(|kind| bus.cart.set_rom_as(&rom, kind).map(|()| kind)),
+        (None, Some(kind)) => bus.cart.set_rom_as(&rom, kind).map(|()| kind),
+        (None, None) => bus.cart.set_rom(&rom).map(|detection| {
+            println!("Detected {} bankswitching ({} confidence)", detection.kind, detection.confidence);
+            detection.kind
+        }),
+    };
+    let kind = match loaded {
+        Ok(kind) => kind,
+        Err(err) => {
+            eprintln!("Failed to load ROM: {}", err);
+            std::process::exit(1);
+        },
+    };
+    if let Some(path) = matches.value_of("ar-loader") {
+        if kind != MapperKind::Supercharger {
+            eprintln!("Warning: ignoring the Supercharger loader, since this isn't a Supercharger (AR) tape image");
+        } else {
+            match std::fs::read(path) {
+                Ok(loader) => bus.cart.set_mapper(Box::new(Supercharger::with_loader(&rom, &loader))),
+                Err(err) => {
+                    eprintln!("Failed to read Supercharger loader {}: {}", path, err);
+                    std::process::exit(1);
+                },
+            }
+        }
+    }
+    
+    if let Some(region) = props.region {
+        bus.tia.region = region;
+    }
+    for (port, controller) in props.controllers.iter().enumerate() {
+        if let Some(controller) = controller {
+            bus.controllers[port] = *controller;
+        }
+    }
+    for (i, difficulty) in props.difficulty.iter().enumerate() {
+        let bit = 0b01000000 << i; // P0 difficulty is bit 6, P1 is bit 7
+        match difficulty {
+            Some(Difficulty::A) => bus.pia.swchb |= bit,
+            Some(Difficulty::B) => bus.pia.swchb &= !bit,
+            None => (),
+        }
+    }
+    let ystart = props.ystart.unwrap_or(0).min(261);
+    let height = props.height.unwrap_or(262).clamp(1, 262 - ystart);
+    
+    let cart_ram = matches.value_of("cart-ram").map(PathBuf::from);
+    if let Some(path) = &cart_ram {
+        if bus.cart.ram().is_none() {
+            eprintln!("Warning: this cartridge has no RAM to keep");
+        } else if path.exists() {
+            match std::fs::read(path) {
+                Ok(data) => if let Err(err) = bus.cart.load_ram(&data) {
+                    eprintln!("Failed to load cartridge RAM from {}: {}", path.display(), err);
+                },
+                Err(err) => eprintln!("Failed to read cartridge RAM from {}: {}", path.display(), err),
+            }
+        }
+    }
+    
+    let power_on = match matches.value_of("seed") {
+        Some(seed) => seed.parse().unwrap_or_else(|err| {
+            eprintln!("Invalid seed: {}", err);
+            std::process::exit(1);
+        }),
+        None => PowerOn::Random(SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos() as u64).unwrap_or_default()),
+    };
+    bus.power_on(power_on);
+    
+    let mut player = None;
+    if let Some(path) = matches.value_of("record") {
+        player = Some(MoviePlayer::record(Movie::new(bus)));
+        println!("Recording movie to {}", path);
+    }
+    if let Some(path) = matches.value_of("play") {
+        let loaded = if is_bk2(path) {
+            bk2::import(path, bus, &rom).map(|(movie, warnings)| {
+                for warning in warnings {
+                    eprintln!("Warning: {}", warning);
+                }
+                movie
+            })
+        } else {
+            Movie::load_file(path)
+        };
+        let movie = match loaded {
+            Ok(movie) => movie,
+            Err(err) => {
+                eprintln!("Failed to load movie: {}", err);
+                std::process::exit(1);
+            },
+        };
+        if let Err(err) = movie.start(bus) {
+            eprintln!("Failed to play movie: {}", err);
+            std::process::exit(1);
+        }
+        println!("Playing movie {} ({} frames)", path, movie.frames.len());
+        player = Some(MoviePlayer::play(movie, true));
+    }
+    if let Some(player) = &mut player {
+        player.record_hashes = matches.is_present("movie-hashes");
+    }
+    println!("Power-on seed: {}", bus.power_on_mode());
+    
+    bus.cpu.init_pc(bus_ref);
+    
+    if let Some(path) = matches.value_of("load-state") {
+        if let Err(err) = slots::load_state_file(bus, path) {
+            eprintln!("Failed to load state: {}", err);
+            std::process::exit(1);
+        }
+        if let Some(player) = &mut player {
+            player.state_loaded(bus);
+        }
+    }
+    
+    let mut window = Window::new("Rustari2600", 228 * 3 / 2, height, WindowOptions {
         borderless: false,
         title: true,
         resize: false,
@@ -33,47 +251,187 @@ fn main() {
         none: false
     }).unwrap();
     
-    let bus_cell = InfCell::new(Bus::default());
-    let bus = bus_cell.get_mut();
-    let bus_ref = bus_cell.get_mut();
+    let mut slots = Slots::new(matches.value_of("rom").unwrap());
+    let mut rewind = Rewind::new(matches.value_of_t("rewind-interval").unwrap_or_else(|err| err.exit()), matches.value_of_t::<usize>("rewind-buffer").unwrap_or_else(|err| err.exit()) * 1024 * 1024);
+    let mut title_frame = bus.frames;
+    let mut run_state = RunState::Running;
+    window.set_title(&title(&slots, bus, run_state));
     
-    bus.cart.set_rom(&std::fs::read(PathBuf::from(matches.value_of("rom").unwrap())).unwrap());
-    bus.cpu.init_pc(bus_ref);
+    // only warn once about a movie's frames not lining up with VSYNC
+    let mut warned_vsync = false;
+    window.limit_update_rate(Some(Duration::from_micros(16600)));
     
-    loop {
+    while window.is_open() && !window.is_key_down(Key::Escape) {
         let start = Instant::now();
-        for _ in 0..(3584160/60) {
-            if window.is_key_down(Key::R) {
-                bus.pia.swchb &= 0b11111110;
-            } else {
-                bus.pia.swchb |= 0b00000001;
+        
+        if window.is_key_down(Key::Backspace) {
+            if let Some(state) = rewind.step_back() {
+                bus.load_state(&state).unwrap();
+                if let Some(player) = &mut player {
+                    player.state_loaded(bus);
+                }
+            }
+            show_frame(bus, &mut window, ystart, height);
+            continue;
+        }
+        
+        if window.is_key_pressed(Key::P, KeyRepeat::No) {
+            run_state = match run_state {
+                RunState::Running => RunState::Paused,
+                RunState::Paused => RunState::Running,
+            };
+            window.set_title(&title(&slots, bus, run_state));
+        }
+        
+        // any of the advance keys pause the emulator, so it stays where it was advanced to
+        // (G advances by frames like F, but skips over lag frames)
+        let advance = [(Key::F, Step::Frame, false), (Key::G, Step::Frame, true), (Key::N, Step::Scanline, false), (Key::I, Step::Instruction, false)].into_iter()
+            .find(|(key, ..)| window.is_key_pressed(*key, KeyRepeat::Yes))
+            .map(|(_, step, skip_lag)| (step, skip_lag));
+        if advance.is_some() && run_state != RunState::Paused {
+            run_state = RunState::Paused;
+            window.set_title(&title(&slots, bus, run_state));
+        }
+        
+        let step = match run_state {
+            RunState::Running => Some((Step::Frame, false)),
+            RunState::Paused => advance,
+        };
+        if let Some((step, skip_lag)) = step {
+            for _ in 0..MAX_LAG_SKIP {
+                let input = match &mut player {
+                    Some(player) => player.input(read_input(&window)),
+                    None => read_input(&window),
+                };
+                let new_frame = run::step(&bus_cell, step, &input);
+                if let Some(err) = bus.cart.take_error() {
+                    eprintln!("Cartridge halted: {}", err);
+                }
+                if step == Step::Frame && !new_frame && !warned_vsync && player.as_ref().is_some_and(|player| player.mode == Mode::Playback) {
+                    eprintln!("Warning: frame {} ran for two frames without a VSYNC, so the movie's frames won't line up with the machine from here on", bus.frames);
+                    warned_vsync = true;
+                }
+                if new_frame {
+                    if let Some(player) = &mut player {
+                        if player.frame_started(input, bus) {
+                            eprintln!("Movie desynced on frame {}: the machine's state doesn't match the movie", player.frame - 1);
+                        }
+                    }
+                    rewind.frame(bus);
+                }
+                
+                // when skipping lag frames, keep going until a frame that read the controllers has ended
+                if !(skip_lag && new_frame && bus.lag) {
+                    break;
+                }
+            }
+        }
+        
+        show_frame(bus, &mut window, ystart, height);
+        if bus.frames != title_frame {
+            title_frame = bus.frames;
+            window.set_title(&title(&slots, bus, run_state));
+        }
+        
+        if let Some(player) = &mut player {
+            if window.is_key_pressed(Key::T, KeyRepeat::No) {
+                player.toggle_read_only();
+                println!("Movie is {}", if player.read_only { "read-only" } else { "read-write" });
             }
-            
-            if window.is_key_down(Key::Right) || window.is_key_pressed(Key::Space, KeyRepeat::No) {
-                bus.tia.cycle(&bus_cell);
+        }
+        if window.is_key_pressed(Key::B, KeyRepeat::No) {
+            println!("Banks: {}", bus.cart.bank_summary());
+        }
+        if let Some(slot) = SLOT_KEYS.iter().position(|key| window.is_key_pressed(*key, KeyRepeat::No)) {
+            slots.current = slot as u8;
+            window.set_title(&title(&slots, bus, run_state));
+        }
+        if window.is_key_pressed(Key::F5, KeyRepeat::No) {
+            match slots.save(bus, ystart, height) {
+                Ok(()) => println!("Saved state to slot {}", slots.current),
+                Err(err) => eprintln!("Failed to save state: {}", err),
             }
-            
-            if update_window(bus, &mut window) {
-                return;
+        }
+        if window.is_key_pressed(Key::F8, KeyRepeat::No) {
+            match slots.load(bus) {
+                Ok(()) => {
+                    println!("Loaded state from slot {}", slots.current);
+                    if let Some(player) = &mut player {
+                        player.state_loaded(bus);
+                    }
+                },
+                Err(err) => eprintln!("Failed to load state: {}", err),
             }
         }
         
-        let elapsed = start.elapsed();
-        if elapsed.as_micros() < 1000000/60 {
-            //std::thread::sleep(Duration::from_micros((999940 - elapsed.as_micros() as u64)/60))
+        if run_state == RunState::Running {
+            println!("time to simulate 1/60 second: {:.6}sec ({}us) ({:.3}fps)", start.elapsed().as_secs_f64(), start.elapsed().as_micros(), 1.0 / start.elapsed().as_secs_f64());
+        }
+    }
+    
+    if let Some(player) = &mut player {
+        player.finish(bus);
+        let name = props.name.as_deref().unwrap_or(&rom_name);
+        if let Some(path) = matches.value_of("record").or_else(|| matches.value_of("play").filter(|_| !player.read_only)) {
+            save_movie(&player.movie, path, &rom, name);
+        }
+        if let Some(path) = matches.value_of("export-bk2") {
+            save_movie(&player.movie, path, &rom, name);
+        }
+    }
+    
+    if let (Some(path), Some(ram)) = (&cart_ram, bus.cart.ram()) {
+        if let Err(err) = std::fs::write(path, ram) {
+            eprintln!("Failed to save cartridge RAM to {}: {}", path.display(), err);
         }
-        println!("time to simulate 1/60 second: {:.6}sec ({}us) ({:.3}fps)", start.elapsed().as_secs_f64(), elapsed.as_micros(), 1.0 / start.elapsed().as_secs_f64());
     }
 }
 
-fn update_window(bus: &mut Bus, window: &mut Window) -> bool {
-    if bus.tia.cycles.color_clock == 0 /*&& bus.tia.cycles.scanline == 0 */{
-        window.update_with_buffer(&bus.tia.framebuffer, 228, 262).unwrap();
-    }
+/// Window title, with the selected savestate slot, the frame and lag frame counters, and whether
+/// the emulator is paused.
+fn title(slots: &Slots, bus: &Bus, run_state: RunState) -> String {
+    let paused = if run_state == RunState::Paused { " - Paused" } else { "" };
+    format!("Rustari2600 - Slot {} - Frame {} ({} lag){}", slots.current, bus.frames, bus.lag_frames, paused)
+}
+
+fn is_bk2(path: &str) -> bool {
+    path.to_ascii_lowercase().ends_with(".bk2")
+}
+
+/// Saves a movie, as a BizHawk movie if the file name ends in `.bk2`.
+fn save_movie(movie: &Movie, path: &str, rom: &[u8], name: &str) {
+    let saved = if is_bk2(path) {
+        bk2::export(movie, path, rom, name).map(|warnings| {
+            for warning in warnings {
+                eprintln!("Warning: {}", warning);
+            }
+        })
+    } else {
+        movie.save_file(path)
+    };
     
-    if window.is_key_down(Key::Escape) || !window.is_open() {
-        return true;
+    match saved {
+        Ok(()) => println!("Saved movie to {} ({} frames)", path, movie.frames.len()),
+        Err(err) => eprintln!("Failed to save movie: {}", err),
+    }
+}
+
+/// Reads the player's input from the keyboard. The arrow keys and space are the left joystick,
+/// and R and S are the console's reset and select switches.
+fn read_input(window: &Window) -> Input {
+    let mut joystick = Joystick::empty();
+    for (key, direction) in [(Key::Up, Joystick::UP), (Key::Down, Joystick::DOWN), (Key::Left, Joystick::LEFT), (Key::Right, Joystick::RIGHT), (Key::Space, Joystick::FIRE)] {
+        joystick.set(direction, window.is_key_down(key));
     }
     
-    false
+    Input {
+        joysticks: [joystick, Joystick::empty()],
+        reset: window.is_key_down(Key::R),
+        select: window.is_key_down(Key::S),
+    }
+}
+
+/// Displays the scanlines from `ystart` to `ystart + height` of the framebuffer.
+fn show_frame(bus: &Bus, window: &mut Window, ystart: usize, height: usize) {
+    window.update_with_buffer(&bus.tia.framebuffer[(ystart * 228)..((ystart + height) * 228)], 228, height).unwrap();
 }
\ No newline at end of file
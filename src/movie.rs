@@ -0,0 +1,481 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use crate::arch::{Bus, Controller, PowerOn};
+use crate::arch::input::{Input, Joystick};
+use crate::arch::tia::Region;
+
+/// First line of every movie file.
+const HEADER: &str = "rustari2600 movie";
+pub const VERSION: u32 = 1;
+
+/// Letters used for each joystick in an input line, in order.
+const JOYSTICK_LETTERS: [(Joystick, char); 5] = [(Joystick::UP, 'U'), (Joystick::DOWN, 'D'), (Joystick::LEFT, 'L'), (Joystick::RIGHT, 'R'), (Joystick::FIRE, 'F')];
+
+/// Recording of the input for every frame since power-on, along with what's needed to start the
+/// machine the same way again.
+///
+/// Movies are plain text, so that edits show up as readable diffs. After a header of `key value`
+/// lines, each frame is one line such as `|U..RF|.....|r.|`: the left joystick, the right joystick,
+/// then the reset and select switches. A `.` is a button that isn't held. Lag frames are marked
+/// with `lag` after that, and frames can also end with the hash of the machine's state, such as
+/// `#0123456789abcdef`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    /// MD5 of the ROM the movie was recorded with.
+    pub rom_md5: String,
+    pub region: Region,
+    /// Devices in the left and right controller ports.
+    pub controllers: [Controller; 2],
+    /// Console switches (SWCHB) at power-on.
+    pub switches: u8,
+    /// How the machine was powered on, written as its seed (or `zero`).
+    pub power_on: PowerOn,
+    pub frames: Vec<Frame>,
+}
+impl Movie {
+    /// Starts an empty movie for a machine that was just powered on.
+    pub fn new(bus: &Bus) -> Self {
+        Self {
+            rom_md5: bus.cart.rom_md5(),
+            region: bus.tia.region,
+            controllers: bus.controllers,
+            switches: bus.pia.swchb,
+            power_on: bus.power_on_mode(),
+            frames: vec![],
+        }
+    }
+    
+    /// Sets up a machine that was just powered on, the same way it was when the movie was recorded.
+    /// Fails if a different ROM is loaded, or different controllers are plugged in.
+    pub fn start(&self, bus: &mut Bus) -> Result<(), String> {
+        let md5 = bus.cart.rom_md5();
+        if md5 != self.rom_md5 {
+            return Err(format!("movie was recorded with ROM {}, but {} is loaded", self.rom_md5, md5));
+        }
+        if bus.controllers != self.controllers {
+            return Err(format!("movie was recorded with {} and {} controllers, but {} and {} are plugged in",
+                self.controllers[0], self.controllers[1], bus.controllers[0], bus.controllers[1]));
+        }
+        
+        bus.tia.region = self.region;
+        bus.pia.swchb = self.switches;
+        bus.power_on(self.power_on);
+        
+        Ok(())
+    }
+    
+    pub fn lag_frames(&self) -> usize {
+        self.frames.iter().filter(|frame| frame.lag).count()
+    }
+    
+    pub fn load_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        
+        text.parse().map_err(|err| format!("{}: {}", path.display(), err))
+    }
+    
+    pub fn save_file<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_string()).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+}
+impl Display for Movie {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "version {}", VERSION)?;
+        writeln!(f, "rom {}", self.rom_md5)?;
+        writeln!(f, "region {}", self.region)?;
+        writeln!(f, "controllers {} {}", self.controllers[0], self.controllers[1])?;
+        writeln!(f, "switches {:02X}", self.switches)?;
+        writeln!(f, "seed {}", self.power_on)?;
+        writeln!(f, "lag_frames {}", self.lag_frames())?;
+        
+        for frame in &self.frames {
+            write!(f, "{}", format_input(&frame.input))?;
+            if frame.lag {
+                write!(f, " lag")?;
+            }
+            if let Some(hash) = frame.hash {
+                write!(f, " #{:016x}", hash)?;
+            }
+            writeln!(f)?;
+        }
+        
+        Ok(())
+    }
+}
+impl std::str::FromStr for Movie {
+    type Err = String;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines().enumerate();
+        if lines.next().map(|(_, line)| line.trim()) != Some(HEADER) {
+            return Err("not a movie file".to_owned());
+        }
+        
+        let mut movie = Movie {
+            rom_md5: String::new(),
+            region: Region::Ntsc,
+            controllers: [Controller::Joystick; 2],
+            switches: 0b00111111,
+            power_on: PowerOn::Zero,
+            frames: vec![],
+        };
+        for (i, line) in lines {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('|') {
+                movie.frames.push(parse_frame(line).map_err(|err| format!("line {}: {}", i + 1, err))?);
+                continue;
+            }
+            
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            let invalid = || format!("line {}: invalid value for {}: \"{}\"", i + 1, key, value);
+            match key {
+                "version" => if value.parse::<u32>().map_err(|_| invalid())? != VERSION {
+                    return Err(format!("movie is version {}, but only version {} is supported", value, VERSION));
+                },
+                "rom" => movie.rom_md5 = value.to_ascii_lowercase(),
+                "region" => movie.region = value.parse().map_err(|_| invalid())?,
+                "controllers" => movie.controllers = match value.split_whitespace().collect::<Vec<_>>().as_slice() {
+                    [left, right] => [left.parse().map_err(|_| invalid())?, right.parse().map_err(|_| invalid())?],
+                    _ => return Err(invalid()),
+                },
+                "switches" => movie.switches = u8::from_str_radix(value, 16).map_err(|_| invalid())?,
+                "seed" => movie.power_on = value.parse().map_err(|_| invalid())?,
+                "lag_frames" => (), // only there for people reading the file, the frames are marked too
+                _ => return Err(format!("line {}: unknown key \"{}\"", i + 1, key)),
+            }
+        }
+        
+        Ok(movie)
+    }
+}
+
+fn format_input(input: &Input) -> String {
+    let mut line = String::from("|");
+    for joystick in &input.joysticks {
+        for (button, letter) in JOYSTICK_LETTERS {
+            line.push(if joystick.contains(button) { letter } else { '.' });
+        }
+        line.push('|');
+    }
+    line.push(if input.reset { 'r' } else { '.' });
+    line.push(if input.select { 's' } else { '.' });
+    line.push('|');
+    
+    line
+}
+
+fn parse_frame(line: &str) -> Result<Frame, String> {
+    let fields: Vec<&str> = line.split('|').collect();
+    let (left, right, switches, rest) = match fields.as_slice() {
+        ["", left, right, switches, rest] => (*left, *right, *switches, *rest),
+        _ => return Err(format!("expected |joystick|joystick|switches|, found \"{}\"", line)),
+    };
+    
+    let mut frame = Frame::default();
+    for word in rest.split_whitespace() {
+        match word.strip_prefix('#') {
+            Some(hash) => frame.hash = Some(u64::from_str_radix(hash, 16).map_err(|_| format!("invalid hash \"{}\"", word))?),
+            None if word == "lag" => frame.lag = true,
+            None => return Err(format!("expected \"lag\" or a #hash after the input, found \"{}\"", word)),
+        }
+    }
+    
+    let held = |field: &str, i: usize, letter: char| match field.chars().nth(i) {
+        Some(c) if c == letter => Ok(true),
+        Some('.') => Ok(false),
+        _ => Err(format!("expected '{}' or '.' in \"{}\"", letter, field)),
+    };
+    
+    let input = &mut frame.input;
+    for (joystick, field) in input.joysticks.iter_mut().zip([left, right]) {
+        for (i, (button, letter)) in JOYSTICK_LETTERS.into_iter().enumerate() {
+            joystick.set(button, held(field, i, letter)?);
+        }
+    }
+    input.reset = held(switches, 0, 'r')?;
+    input.select = held(switches, 1, 's')?;
+    
+    Ok(frame)
+}
+
+/// One frame of a movie.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Frame {
+    /// Input applied at the start of the frame.
+    pub input: Input,
+    /// Whether the game didn't read the controllers during the frame.
+    pub lag: bool,
+    /// Hash of the machine's state at the start of the frame, after the input was applied.
+    pub hash: Option<u64>,
+}
+
+/// Whether a movie's input is being recorded or played back.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mode {
+    Record,
+    Playback,
+    /// Played back to the end in read-only mode. The player has control, but nothing is recorded.
+    Finished,
+}
+
+/// Records or plays back a movie, one frame at a time.
+#[derive(Clone, Debug)]
+pub struct MoviePlayer {
+    pub movie: Movie,
+    pub mode: Mode,
+    /// While playing back, ignore the player's input instead of letting them take over recording.
+    pub read_only: bool,
+    /// Number of frames that have started since power-on. This has to match `Bus::frames`, so it's
+    /// moved along with the machine when a state is loaded (see [`MoviePlayer::state_loaded`]).
+    pub frame: usize,
+    /// Save the hash of the machine's state for every recorded frame.
+    pub record_hashes: bool,
+    /// First frame where the machine's state didn't match the hash in the movie.
+    pub desync: Option<usize>,
+}
+impl MoviePlayer {
+    pub fn record(movie: Movie) -> Self {
+        Self { movie, mode: Mode::Record, read_only: false, frame: 0, record_hashes: false, desync: None }
+    }
+    
+    pub fn play(movie: Movie, read_only: bool) -> Self {
+        Self { movie, mode: Mode::Playback, read_only, frame: 0, record_hashes: false, desync: None }
+    }
+    
+    /// Switches between read-only and read-write. A movie that was played to the end in read-only
+    /// mode continues recording when switched to read-write.
+    pub fn toggle_read_only(&mut self) {
+        self.read_only = !self.read_only;
+        if self.mode == Mode::Finished && !self.read_only {
+            self.mode = Mode::Record;
+        }
+    }
+    
+    /// Should be called before saving a recorded movie. A frame's lag flag is only set once the
+    /// next frame starts, so the frame in progress is marked by whether the game has read the
+    /// controllers yet.
+    pub fn finish(&mut self, bus: &Bus) {
+        if self.mode == Mode::Record {
+            if let Some(last) = self.frame.checked_sub(1).and_then(|i| self.movie.frames.get_mut(i)) {
+                last.lag = !bus.input_polled;
+            }
+        }
+    }
+    
+    /// Should be called after a state was loaded into the machine, like when rewinding, to move to
+    /// the frame the machine is now on.
+    ///
+    /// In read-only mode the movie plays back from there. Otherwise, while recording (or once
+    /// there's nothing left to play back), the frames after that are dropped.
+    pub fn state_loaded(&mut self, bus: &Bus) {
+        self.frame = bus.frames;
+        if self.read_only {
+            self.mode = if self.frame < self.movie.frames.len() { Mode::Playback } else { Mode::Finished };
+        } else if self.mode == Mode::Record || self.frame >= self.movie.frames.len() {
+            self.movie.frames.truncate(self.frame);
+            self.mode = Mode::Record;
+        }
+    }
+    
+    /// Input to apply at the start of the next frame, given what the player is currently holding.
+    ///
+    /// While playing back in read-write mode, holding anything takes over: the movie is cut off at
+    /// the current frame, and recording continues from there.
+    pub fn input(&mut self, live: Input) -> Input {
+        if self.mode == Mode::Playback && !self.read_only && live != Input::default() {
+            self.movie.frames.truncate(self.frame);
+            self.mode = Mode::Record;
+        }
+        
+        match self.mode {
+            Mode::Playback => self.movie.frames.get(self.frame).map_or(live, |frame| frame.input),
+            Mode::Record | Mode::Finished => live,
+        }
+    }
+    
+    /// Should be called whenever a new frame started, with the input that was applied to it.
+    ///
+    /// While playing back, the machine's state is checked against the hash in the movie, if it has
+    /// one. Returns true if this is the first frame that doesn't match.
+    pub fn frame_started(&mut self, input: Input, bus: &Bus) -> bool {
+        let mut desynced = false;
+        match self.mode {
+            Mode::Record => {
+                if let Some(previous) = self.frame.checked_sub(1).and_then(|i| self.movie.frames.get_mut(i)) {
+                    previous.lag = bus.lag;
+                }
+                self.movie.frames.truncate(self.frame);
+                self.movie.frames.push(Frame { input, lag: false, hash: self.record_hashes.then(|| bus.state_hash()) });
+            },
+            Mode::Playback => if let Some(hash) = self.movie.frames.get(self.frame).and_then(|frame| frame.hash) {
+                if self.desync.is_none() && hash != bus.state_hash() {
+                    self.desync = Some(self.frame);
+                    desynced = true;
+                }
+            },
+            Mode::Finished => (),
+        }
+        self.frame += 1;
+        
+        if self.mode == Mode::Playback && self.frame >= self.movie.frames.len() {
+            self.mode = if self.read_only { Mode::Finished } else { Mode::Record };
+        }
+        
+        desynced
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::BusAccessable;
+    use crate::arch::tests::machine;
+    use crate::run::{step, Step};
+    
+    /// Loop that starts a new frame every few scanlines.
+    const PROGRAM: [u8; 25] = [0xE6, 0x80, 0xA5, 0x80, 0x85, 0x08, 0x85, 0x0E, 0x85, 0x02, 0xA9, 0x02, 0x85, 0x00,
+        0x85, 0x02, 0x85, 0x02, 0xA9, 0x00, 0x85, 0x00, 0x4C, 0x00, 0xF0];
+    
+    /// Input that's different on every frame.
+    fn input(frame: usize) -> Input {
+        Input {
+            joysticks: [Joystick::from_bits_truncate(frame as u8), Joystick::from_bits_truncate((frame / 32) as u8)],
+            reset: frame % 3 == 1,
+            select: frame % 5 == 1,
+        }
+    }
+    
+    #[test]
+    fn round_trip() {
+        let mut movie = Movie::new(&Bus::default());
+        movie.power_on = PowerOn::Random(1234);
+        movie.region = Region::Pal;
+        movie.switches = 0b00001011;
+        movie.frames = (0..100).map(|i| Frame { input: input(i), lag: i % 7 == 0, hash: (i % 2 == 0).then_some(i as u64 * 0x0123456789) }).collect();
+        
+        let text = movie.to_string();
+        let parsed: Movie = text.parse().unwrap();
+        assert_eq!(parsed, movie);
+        assert_eq!(parsed.to_string(), text);
+        assert!(text.contains("\n|.....|.....|..| lag #0000000000000000\n"));
+        
+        assert!("|U....|.....|..|".parse::<Movie>().is_err());
+        assert!(format!("{}\n|X....|.....|..|\n", HEADER).parse::<Movie>().is_err());
+        assert!(format!("{}\n|U....|.....|..| lag #xyz\n", HEADER).parse::<Movie>().is_err());
+        assert!(format!("{}\nversion 99\n", HEADER).parse::<Movie>().is_err());
+    }
+    
+    #[test]
+    fn finish_marks_last_frame() {
+        for polls in [false, true] {
+            // LDA INPT4 in front of the loop, when the game should read the controllers
+            let program: Vec<u8> = if polls { [&[0xA5, 0x0C][..], &PROGRAM].concat() } else { PROGRAM.to_vec() };
+            let bus_cell = machine(&program);
+            let bus = bus_cell.get_mut();
+            let mut player = MoviePlayer::record(Movie::new(bus));
+            for _ in 0..5 {
+                step(&bus_cell, Step::Frame, &Input::default());
+                player.frame_started(Input::default(), bus);
+            }
+            // far enough into the last frame for the game to have read the controllers
+            for _ in 0..4 {
+                step(&bus_cell, Step::Scanline, &Input::default());
+            }
+            
+            player.finish(bus);
+            assert_eq!(player.movie.frames.iter().map(|frame| frame.lag).collect::<Vec<_>>(), [!polls; 5]);
+        }
+    }
+    
+    #[test]
+    fn desync() {
+        let bus_cell = machine(&PROGRAM);
+        let bus = bus_cell.get_mut();
+        let power_on = bus.save_state();
+        let mut player = MoviePlayer::record(Movie::new(bus));
+        player.record_hashes = true;
+        for i in 0..10 {
+            let input = player.input(input(i));
+            step(&bus_cell, Step::Frame, &input);
+            player.frame_started(input, bus);
+        }
+        assert!(player.movie.frames.iter().all(|frame| frame.hash.is_some()));
+        
+        // playing back on the same machine matches, until the machine is changed behind its back
+        let movie = player.movie.clone();
+        for tamper in [None, Some(6)] {
+            bus.load_state(&power_on).unwrap();
+            let mut player = MoviePlayer::play(movie.clone(), true);
+            for i in 0..10 {
+                if tamper == Some(i) {
+                    let value = bus.read(0x00FF);
+                    bus.write(0x00FF, value ^ 1);
+                }
+                let input = player.input(Input::default());
+                step(&bus_cell, Step::Frame, &input);
+                assert_eq!(player.frame_started(input, bus), tamper == Some(i));
+            }
+            assert_eq!(player.desync, tamper);
+        }
+    }
+    
+    #[test]
+    fn state_loaded() {
+        let bus_cell = machine(&PROGRAM);
+        let bus = bus_cell.get_mut();
+        let mut player = MoviePlayer::record(Movie::new(bus));
+        let mut saved = vec![];
+        for i in 0..20 {
+            if i == 10 {
+                saved = bus.save_state();
+            }
+            let input = player.input(input(i));
+            assert!(step(&bus_cell, Step::Frame, &input));
+            player.frame_started(input, bus);
+        }
+        let recorded = player.movie.clone();
+        
+        // loading while recording drops the frames after the state
+        bus.load_state(&saved).unwrap();
+        player.state_loaded(bus);
+        assert_eq!(player.frame, bus.frames);
+        assert_eq!(player.movie.frames, recorded.frames[..bus.frames]);
+        for i in bus.frames..20 {
+            let input = player.input(input(i));
+            step(&bus_cell, Step::Frame, &input);
+            player.frame_started(input, bus);
+        }
+        assert_eq!(player.movie.frames, recorded.frames);
+        
+        // loading while playing back read-only picks up the movie from the state's frame
+        let mut player = MoviePlayer::play(recorded.clone(), true);
+        player.frame = 20;
+        player.mode = Mode::Finished;
+        bus.load_state(&saved).unwrap();
+        player.state_loaded(bus);
+        assert_eq!(player.mode, Mode::Playback);
+        assert_eq!(player.input(Input::default()), recorded.frames[bus.frames].input);
+        assert_eq!(player.movie, recorded);
+    }
+    
+    #[test]
+    fn controllers() {
+        let mut bus = Bus::default();
+        bus.controllers = [Controller::Paddles, Controller::None];
+        let movie = Movie::new(&bus);
+        let parsed: Movie = movie.to_string().parse().unwrap();
+        assert_eq!(parsed.controllers, [Controller::Paddles, Controller::None]);
+        assert!(parsed.start(&mut bus).is_ok());
+        
+        bus.controllers = [Controller::Joystick; 2];
+        assert!(parsed.start(&mut bus).is_err(), "movie started with the wrong controllers");
+        assert!(format!("{}\ncontrollers JOYSTICK\n", HEADER).parse::<Movie>().is_err());
+        assert!(format!("{}\ncontrollers JOYSTICK WHEEL\n", HEADER).parse::<Movie>().is_err());
+    }
+}
\ No newline at end of file
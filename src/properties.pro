@@ -0,0 +1,80 @@
+; Built-in game properties, keyed by the MD5 of the ROM image.
+;
+; Uses the same format as Stella's stella.pro, so entries can be copied over from there. Settings
+; given here are applied before any bankswitching detection; a local file passed with
+; --properties overrides them, and can set any of them back to AUTO.
+;
+; "Cart.MD5" "<md5>"
+; "Cart.Name" "<name>"
+; "Cart.Type" "AUTO | 2K | 4K | F8 | F8SC | ... | AR"
+; "Display.Format" "AUTO | NTSC | PAL | SECAM"
+; "Controller.Left" "AUTO | JOYSTICK | PADDLES | KEYBOARD | DRIVING | NONE"
+; "Controller.Right" "AUTO | JOYSTICK | PADDLES | KEYBOARD | DRIVING | NONE"
+; "Console.LeftDifficulty" "AUTO | A | B"
+; "Console.RightDifficulty" "AUTO | A | B"
+; "Display.YStart" "AUTO | <first visible scanline after VSYNC>"
+; "Display.Height" "AUTO | <number of visible scanlines>"
+; ""
+
+"Cart.MD5" "3347a6dd59049b15a38394aa2dafa585"
+"Cart.Name" "Montezuma's Revenge (1984) (Parker Bros)"
+"Cart.Type" "E0"
+""
+
+"Cart.MD5" "081e2c114c9c20b61acf25fc95c71bf4"
+"Cart.Name" "Frogger II - Threeedeep! (1984) (Parker Bros)"
+"Cart.Type" "E0"
+""
+
+"Cart.MD5" "6d842c96d5a01967be9680080dd5be54"
+"Cart.Name" "Pitfall II - Lost Caverns (1984) (Activision)"
+"Cart.Type" "DPC"
+"Display.Format" "NTSC"
+"Display.YStart" "37"
+"Display.Height" "192"
+""
+
+"Cart.MD5" "ac7c2260378975614192ca2bc3d20e0b"
+"Cart.Name" "Decathlon (1983) (Activision)"
+"Cart.Type" "FE"
+""
+
+"Cart.MD5" "fbb0151ea2108e33b2dbaae14a1831dd"
+"Cart.Name" "Robot Tank (1983) (Activision)"
+"Cart.Type" "FE"
+"Display.Format" "NTSC"
+"Display.YStart" "37"
+"Display.Height" "192"
+""
+
+"Cart.MD5" "3b76242691730b2dd22ec0ceab351bc6"
+"Cart.Name" "Miner 2049er (1982) (Tigervision)"
+"Cart.Type" "3F"
+""
+
+"Cart.MD5" "f34f08e5eb96e500e851a80be3277a56"
+"Cart.Name" "Breakout (1978) (Atari)"
+"Controller.Left" "PADDLES"
+""
+
+"Cart.MD5" "8885d0ce11c5b40c3a8a8d9ed28cefef"
+"Cart.Name" "Super Breakout (1981) (Atari)"
+"Controller.Left" "PADDLES"
+""
+
+"Cart.MD5" "5428cdfada281c569c74c7308c7f2c26"
+"Cart.Name" "Kaboom! (1981) (Activision)"
+"Controller.Left" "PADDLES"
+""
+
+"Cart.MD5" "cbe5a166550a8129a5e6d374901dffad"
+"Cart.Name" "Warlords (1981) (Atari)"
+"Controller.Left" "PADDLES"
+"Controller.Right" "PADDLES"
+""
+
+"Cart.MD5" "02cee0b140d2f1a1efcfb1d482a5c392"
+"Cart.Name" "Indy 500 (1977) (Atari)"
+"Controller.Left" "DRIVING"
+"Controller.Right" "DRIVING"
+""
\ No newline at end of file
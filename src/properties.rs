@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::path::Path;
+use crate::arch::Controller;
+use crate::arch::mapper::detect::MapperKind;
+use crate::arch::tia::Region;
+
+/// Built-in game properties, in the same format as user override files.
+const BUILTIN: &str = include_str!("properties.pro");
+
+/// Position of a console difficulty switch.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Difficulty {
+    /// Pro
+    A,
+    /// Amateur
+    B,
+}
+
+/// Per-game settings, like Stella's game properties. Anything that's None is left at its default.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Properties {
+    pub name: Option<String>,
+    pub mapper: Option<MapperKind>,
+    pub region: Option<Region>,
+    /// Left and right controller ports.
+    pub controllers: [Option<Controller>; 2],
+    /// Left and right difficulty switches.
+    pub difficulty: [Option<Difficulty>; 2],
+    /// First scanline of the visible picture.
+    pub ystart: Option<usize>,
+    /// Number of visible scanlines.
+    pub height: Option<usize>,
+}
+impl Properties {
+    /// Changes one setting. `AUTO` puts any setting back to its default, so that an override
+    /// file can undo a setting from the built-in properties.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value for {}: \"{}\"", key, value);
+        let auto = value.eq_ignore_ascii_case("AUTO");
+        
+        match key {
+            "Cart.Name" => self.name = Some(value.to_owned()),
+            "Cart.Type" => self.mapper = if auto { None } else { Some(value.parse().map_err(|_| invalid())?) },
+            "Display.Format" => self.region = if auto { None } else { Some(value.parse().map_err(|_| invalid())?) },
+            "Controller.Left" | "Controller.Right" => {
+                let controller = if auto { None } else { Some(value.parse::<Controller>().map_err(|_| invalid())?) };
+                self.controllers[(key == "Controller.Right") as usize] = controller;
+            },
+            "Console.LeftDifficulty" | "Console.RightDifficulty" => {
+                let difficulty = match value.to_ascii_uppercase().as_str() {
+                    "AUTO" => None,
+                    "A" => Some(Difficulty::A),
+                    "B" => Some(Difficulty::B),
+                    _ => return Err(invalid()),
+                };
+                self.difficulty[(key == "Console.RightDifficulty") as usize] = difficulty;
+            },
+            "Display.YStart" => self.ystart = if auto { None } else { Some(value.parse().map_err(|_| invalid())?) },
+            "Display.Height" => self.height = if auto { None } else { Some(value.parse().map_err(|_| invalid())?) },
+            _ => (), // keys used by Stella that don't apply here
+        }
+        
+        Ok(())
+    }
+}
+
+/// Game properties keyed by the MD5 of the ROM image (lowercase hex).
+///
+/// The format is the one used by Stella's `stella.pro`, so entries can be copied over directly.
+/// Each line holds a quoted key and value, and a line with just `""` ends the entry:
+///
+/// ```text
+/// "Cart.MD5" "0123456789abcdef0123456789abcdef"
+/// "Cart.Name" "Some Game"
+/// "Cart.Type" "F8SC"
+/// "Console.RightDifficulty" "A"
+/// "Controller.Left" "PADDLES"
+/// "Display.Format" "PAL"
+/// "Display.YStart" "40"
+/// "Display.Height" "210"
+/// ""
+/// ```
+///
+/// Lines starting with `;` are comments. Unknown keys are ignored. When a ROM already has an
+/// entry, a later entry for it only changes the settings it lists, and a value of `AUTO` puts a
+/// setting back to its default.
+#[derive(Clone, Debug, Default)]
+pub struct PropertiesDb {
+    entries: HashMap<String, Properties>,
+}
+impl PropertiesDb {
+    /// Creates a database holding the built-in properties.
+    pub fn builtin() -> Self {
+        let mut db = Self::default();
+        db.parse(BUILTIN).unwrap();
+        
+        db
+    }
+    
+    /// Adds the entries from a properties file, overriding the settings they contain.
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        
+        self.parse(&text).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+    
+    pub fn parse(&mut self, text: &str) -> Result<(), String> {
+        let mut md5 = None;
+        // settings of the current entry, with their line numbers
+        let mut settings = vec![];
+        
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            
+            let fields: Vec<&str> = line.split('"').collect();
+            match fields.as_slice() {
+                ["", "", ""] => { // end of entry
+                    match md5.take() {
+                        Some(md5) => self.insert(md5, &settings)?,
+                        None => return Err(format!("line {}: entry has no Cart.MD5", i + 1)),
+                    }
+                    settings.clear();
+                },
+                ["", key, middle, value, ""] if middle.trim().is_empty() => {
+                    if *key == "Cart.MD5" {
+                        md5 = Some(value.to_ascii_lowercase());
+                    } else {
+                        settings.push((i, *key, *value));
+                    }
+                },
+                _ => return Err(format!("line {}: expected \"Key\" \"Value\"", i + 1)),
+            }
+        }
+        
+        if let Some(md5) = md5 {
+            self.insert(md5, &settings)?;
+        }
+        
+        Ok(())
+    }
+    
+    /// Applies an entry's settings, in order, on top of any existing entry for the same ROM.
+    fn insert(&mut self, md5: String, settings: &[(usize, &str, &str)]) -> Result<(), String> {
+        let mut props = self.entries.get(&md5).cloned().unwrap_or_default();
+        for (i, key, value) in settings {
+            props.set(key, value).map_err(|err| format!("line {}: {}", i + 1, err))?;
+        }
+        self.entries.insert(md5, props);
+        
+        Ok(())
+    }
+    
+    pub fn get(&self, md5: &str) -> Option<&Properties> {
+        self.entries.get(md5)
+    }
+}
+
+/// MD5 of a ROM image, as used to look up its properties.
+pub fn md5(rom: &[u8]) -> String {
+    format!("{:x}", md5::compute(rom))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn builtin() {
+        let db = PropertiesDb::builtin();
+        let pitfall2 = db.get("6d842c96d5a01967be9680080dd5be54").unwrap();
+        assert_eq!(pitfall2.mapper, Some(MapperKind::Dpc));
+        assert_eq!(pitfall2.region, Some(Region::Ntsc));
+        assert_eq!((pitfall2.ystart, pitfall2.height), (Some(37), Some(192)));
+        let warlords = db.get("cbe5a166550a8129a5e6d374901dffad").unwrap();
+        assert_eq!(warlords.controllers, [Some(Controller::Paddles); 2]);
+    }
+    
+    #[test]
+    fn parse() {
+        let mut db = PropertiesDb::default();
+        db.parse("; comment\n\"Cart.MD5\" \"ABCDEF\"\n\"Cart.Type\" \"F8SC\"\n\"Controller.Left\" \"PADDLES\"\n\
+            \"Console.RightDifficulty\" \"A\"\n\"Display.YStart\" \"40\"\n\"Display.Phosphor\" \"YES\"\n\"\"\n").unwrap();
+        let props = db.get("abcdef").unwrap();
+        assert_eq!(props.controllers, [Some(Controller::Paddles), None]);
+        assert_eq!(props.difficulty, [None, Some(Difficulty::A)]);
+        assert_eq!(props.ystart, Some(40));
+        assert!(props.mapper.is_some());
+        
+        // a later entry only changes what it lists
+        db.parse("\"Cart.MD5\" \"abcdef\"\n\"Display.YStart\" \"30\"\n\"\"").unwrap();
+        let props = db.get("abcdef").unwrap();
+        assert_eq!(props.ystart, Some(30));
+        assert!(props.mapper.is_some());
+        
+        assert!(db.parse("\"Cart.MD5\" \"abcdef\"\n\"Cart.Type\" \"XYZ\"\n").is_err());
+        assert!(db.parse("\"Cart.Type\" \"F8\"\n\"\"\n").is_err());
+        assert_eq!(md5(b""), "d41d8cd98f00b204e9800998ecf8427e");
+    }
+    
+    #[test]
+    fn auto_clears_builtin() {
+        let mut db = PropertiesDb::builtin();
+        db.parse("\"Cart.MD5\" \"6d842c96d5a01967be9680080dd5be54\"\n\"Cart.Type\" \"AUTO\"\n\
+            \"Display.Format\" \"auto\"\n\"Display.YStart\" \"AUTO\"\n\"Display.Height\" \"AUTO\"\n\"\"\n").unwrap();
+        let props = db.get("6d842c96d5a01967be9680080dd5be54").unwrap();
+        assert_eq!((props.mapper, props.region, props.ystart, props.height), (None, None, None, None));
+        assert!(props.name.is_some());
+        
+        db.parse("\"Cart.MD5\" \"cbe5a166550a8129a5e6d374901dffad\"\n\"Controller.Right\" \"AUTO\"\n\"\"\n").unwrap();
+        let props = db.get("cbe5a166550a8129a5e6d374901dffad").unwrap();
+        assert_eq!(props.controllers, [Some(Controller::Paddles), None]);
+    }
+}
\ No newline at end of file
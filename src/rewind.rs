@@ -0,0 +1,193 @@
+use std::collections::VecDeque;
+use crate::arch::Bus;
+
+/// History of machine states for rewinding, snapshotted every `interval` frames.
+///
+/// Only the newest state is kept as is. Every older one is stored as the XOR of itself and the
+/// state after it, run-length encoded. Since little changes from one frame to the next, that
+/// delta is mostly zeros and compresses to a small fraction of a full state. Stepping back
+/// XORs the newest delta into the newest state, so it never needs to decode more than one delta.
+#[derive(Clone, Debug)]
+pub struct Rewind {
+    interval: u32,
+    /// Maximum number of bytes used by the compressed deltas.
+    capacity: usize,
+    frames: u32,
+    latest: Option<Vec<u8>>,
+    /// Deltas from each state to the previous one, oldest first.
+    deltas: VecDeque<Vec<u8>>,
+    size: usize,
+}
+impl Rewind {
+    pub fn new(interval: u32, capacity: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            capacity,
+            frames: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+            size: 0,
+        }
+    }
+    
+    /// Should be called once at the start of every frame. Snapshots the machine if it's time to.
+    pub fn frame(&mut self, bus: &Bus) {
+        self.frames += 1;
+        if self.frames >= self.interval {
+            self.frames = 0;
+            self.push(bus.save_state());
+        }
+    }
+    
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.latest.take() {
+            let delta = encode_delta(&previous, &state);
+            self.size += delta.len();
+            self.deltas.push_back(delta);
+            
+            while self.size > self.capacity {
+                match self.deltas.pop_front() {
+                    Some(oldest) => self.size -= oldest.len(),
+                    None => break,
+                }
+            }
+        }
+        
+        self.latest = Some(state);
+    }
+    
+    /// Steps back to the previous snapshot, returning its state. The newest snapshot is dropped,
+    /// so calling this repeatedly walks further back. Returns None once the history runs out.
+    pub fn step_back(&mut self) -> Option<Vec<u8>> {
+        let delta = self.deltas.pop_back()?;
+        self.size -= delta.len();
+        
+        let latest = self.latest.as_mut().unwrap();
+        apply_delta(latest, &delta);
+        self.frames = 0;
+        
+        Some(latest.clone())
+    }
+}
+
+/// Encodes `previous` as a delta against `next`: the length of `previous`, then the XOR of the
+/// two (padded with zeros to the longer length) as runs. Each run is a varint count of zero bytes
+/// followed by a varint count of literal bytes and the literals themselves.
+pub(crate) fn encode_delta(previous: &[u8], next: &[u8]) -> Vec<u8> {
+    let len = previous.len().max(next.len());
+    let xor = |i: usize| previous.get(i).copied().unwrap_or(0) ^ next.get(i).copied().unwrap_or(0);
+    
+    let mut out = vec![];
+    write_varint(&mut out, previous.len());
+    
+    let mut i = 0;
+    while i < len {
+        let zeros_start = i;
+        while i < len && xor(i) == 0 {
+            i += 1;
+        }
+        let literals_start = i;
+        // a single zero byte between literals is cheaper to keep as a literal than to start a new run
+        while i < len && (xor(i) != 0 || (i + 1 < len && xor(i + 1) != 0)) {
+            i += 1;
+        }
+        
+        write_varint(&mut out, literals_start - zeros_start);
+        write_varint(&mut out, i - literals_start);
+        out.extend((literals_start..i).map(xor));
+    }
+    
+    out
+}
+
+/// Turns `state` back into the state a delta was made from.
+pub(crate) fn apply_delta(state: &mut Vec<u8>, delta: &[u8]) {
+    let mut pos = 0;
+    let previous_len = read_varint(delta, &mut pos);
+    let len = previous_len.max(state.len());
+    state.resize(len, 0);
+    
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let literals = read_varint(delta, &mut pos);
+        for (byte, xor) in state[i..(i + literals)].iter_mut().zip(&delta[pos..(pos + literals)]) {
+            *byte ^= xor;
+        }
+        i += literals;
+        pos += literals;
+    }
+    
+    state.truncate(previous_len);
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+        
+        if byte & 0x80 == 0 {
+            return value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::input::Input;
+    use crate::arch::tests::machine;
+    use crate::run::{step, Step};
+    
+    #[test]
+    fn deltas() {
+        let mut rewind = Rewind::new(1, usize::MAX);
+        rewind.push(vec![1, 2, 3, 0, 0, 0, 0, 5]);
+        rewind.push(vec![1, 2]);
+        rewind.push(vec![9; 300]);
+        assert_eq!(rewind.step_back(), Some(vec![1, 2]));
+        assert_eq!(rewind.step_back(), Some(vec![1, 2, 3, 0, 0, 0, 0, 5]));
+        assert_eq!(rewind.step_back(), None);
+        
+        // each of these deltas takes 205 bytes, so only the newest one fits
+        let mut rewind = Rewind::new(1, 300);
+        for i in 0..10 {
+            rewind.push(vec![i; 200]);
+        }
+        assert_eq!(rewind.step_back(), Some(vec![8; 200]));
+        assert_eq!(rewind.step_back(), None);
+    }
+    
+    #[test]
+    fn step_back() {
+        // loop: INC $80; LDA $80; STA COLUPF; STA PF1; STA WSYNC; VSYNC for three scanlines; JMP loop
+        let bus_cell = machine(&[0xE6, 0x80, 0xA5, 0x80, 0x85, 0x08, 0x85, 0x0E, 0x85, 0x02, 0xA9, 0x02, 0x85, 0x00,
+            0x85, 0x02, 0x85, 0x02, 0xA9, 0x00, 0x85, 0x00, 0x4C, 0x00, 0xF0]);
+        let bus = bus_cell.get_mut();
+        let mut rewind = Rewind::new(1, usize::MAX);
+        let mut states = vec![];
+        for _ in 0..30 {
+            step(&bus_cell, Step::Frame, &Input::default());
+            rewind.frame(bus);
+            states.push(bus.save_state());
+        }
+        
+        states.pop();
+        while let Some(state) = rewind.step_back() {
+            assert!(state == states.pop().unwrap(), "rewound to the wrong state");
+        }
+        assert!(states.is_empty());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,209 @@
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+/// File extensions used for 2600 ROM images.
+const ROM_EXTENSIONS: [&str; 3] = ["a26", "bin", "rom"];
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(std::io::Error),
+    Zip(zip::result::ZipError),
+    /// The archive doesn't hold any files that look like ROM images.
+    NoRom,
+    /// The archive holds several ROM images and none was selected. Holds their names.
+    Ambiguous(Vec<String>),
+    /// The selected ROM image isn't in the archive.
+    NotFound(String, Vec<String>),
+}
+impl Display for RomError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RomError::Io(err) => write!(f, "{}", err),
+            RomError::Zip(err) => write!(f, "bad zip archive: {}", err),
+            RomError::NoRom => write!(f, "archive doesn't contain a ROM image (.{})", ROM_EXTENSIONS.join(", .")),
+            RomError::Ambiguous(names) => write!(f, "archive contains several ROM images, select one by name or index:{}", list(names)),
+            RomError::NotFound(select, names) => write!(f, "archive doesn't contain \"{}\", it contains:{}", select, list(names)),
+        }
+    }
+}
+impl std::error::Error for RomError {}
+impl From<std::io::Error> for RomError {
+    fn from(err: std::io::Error) -> Self {
+        RomError::Io(err)
+    }
+}
+impl From<zip::result::ZipError> for RomError {
+    fn from(err: zip::result::ZipError) -> Self {
+        RomError::Zip(err)
+    }
+}
+
+fn list(names: &[String]) -> String {
+    names.iter().enumerate().map(|(i, name)| format!("\n  {}: {}", i, name)).collect()
+}
+
+/// ROM image read from disk, after decompression.
+#[derive(Clone, Debug)]
+pub struct Rom {
+    /// File name of the image (the entry name, for archives).
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Reads a ROM image, which may be plain or inside a .zip or .gz archive.
+///
+/// A zip archive can hold several files. Only the ones with a ROM extension are considered, and if
+/// there is more than one, `select` has to give either its name (the path in the archive, or just
+/// the file name) or its index among them.
+pub fn load<P: AsRef<Path>>(path: P, select: Option<&str>) -> Result<Rom, RomError> {
+    let path = path.as_ref();
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    
+    match path.extension().map(|ext| ext.to_string_lossy().to_ascii_lowercase()).as_deref() {
+        Some("zip") => load_zip(File::open(path)?, select),
+        Some("gz") => {
+            let mut decoder = GzDecoder::new(File::open(path)?);
+            let mut data = vec![];
+            decoder.read_to_end(&mut data)?;
+            
+            // the original file name is optional in gzip headers
+            let name = decoder.header()
+                .and_then(|header| header.filename())
+                .map(|name| String::from_utf8_lossy(name).into_owned())
+                .or_else(|| path.file_stem().map(|stem| stem.to_string_lossy().into_owned()))
+                .unwrap_or(name);
+            
+            Ok(Rom { name, data })
+        },
+        _ => Ok(Rom { name, data: std::fs::read(path)? }),
+    }
+}
+
+fn load_zip(file: File, select: Option<&str>) -> Result<Rom, RomError> {
+    let mut archive = ZipArchive::new(file)?;
+    
+    let mut names = vec![];
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        let is_rom = Path::new(entry.name()).extension()
+            .map(|ext| ROM_EXTENSIONS.contains(&ext.to_string_lossy().to_ascii_lowercase().as_str()))
+            .unwrap_or(false);
+        if entry.is_file() && is_rom {
+            names.push(entry.name().to_owned());
+        }
+    }
+    
+    let name = match (select, names.len()) {
+        (_, 0) => return Err(RomError::NoRom),
+        (None, 1) => names[0].clone(),
+        (None, _) => return Err(RomError::Ambiguous(names)),
+        (Some(select), _) => {
+            let found = names.iter().find(|name| {
+                name.eq_ignore_ascii_case(select) || Path::new(name).file_name().map(|file| file.to_string_lossy().eq_ignore_ascii_case(select)).unwrap_or(false)
+            }).or_else(|| select.parse::<usize>().ok().and_then(|i| names.get(i)));
+            
+            match found {
+                Some(name) => name.clone(),
+                None => return Err(RomError::NotFound(select.to_owned(), names)),
+            }
+        },
+    };
+    
+    let mut data = vec![];
+    archive.by_name(&name)?.read_to_end(&mut data)?;
+    
+    Ok(Rom { name, data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use flate2::{Compression, GzBuilder};
+    use flate2::write::GzEncoder;
+    use zip::ZipWriter;
+    use zip::write::FileOptions;
+    
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rustari2600-test-{}-{}", std::process::id(), name))
+    }
+    
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        for (name, data) in entries {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+    
+    #[test]
+    fn zip_selection() {
+        let path = temp_path("roms.zip");
+        write_zip(&path, &[("readme.txt", b"hi"), ("pal/Game.a26", &[1; 4]), ("Game (NTSC).BIN", &[2; 4])]);
+        
+        let rom = |select| load(&path, select).map(|rom| (rom.name, rom.data));
+        let ambiguous = rom(None);
+        let by_path = rom(Some("pal/game.a26")).unwrap();
+        let by_file_name = rom(Some("game (ntsc).bin")).unwrap();
+        let by_index = rom(Some("1")).unwrap();
+        let not_found = rom(Some("2"));
+        std::fs::remove_file(&path).unwrap();
+        
+        assert_eq!(by_path, ("pal/Game.a26".to_owned(), vec![1; 4]));
+        assert_eq!(by_file_name, ("Game (NTSC).BIN".to_owned(), vec![2; 4]));
+        assert_eq!(by_index, by_file_name);
+        // the readme isn't a ROM image, so it's neither listed nor counted
+        let names = vec!["pal/Game.a26".to_owned(), "Game (NTSC).BIN".to_owned()];
+        assert!(matches!(ambiguous, Err(RomError::Ambiguous(found)) if found == names));
+        assert!(matches!(not_found, Err(RomError::NotFound(select, found)) if select == "2" && found == names));
+    }
+    
+    #[test]
+    fn zip_without_roms() {
+        let path = temp_path("empty.zip");
+        write_zip(&path, &[("readme.txt", b"hi")]);
+        let result = load(&path, None);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(RomError::NoRom)));
+        
+        let path = temp_path("single.zip");
+        write_zip(&path, &[("readme.txt", b"hi"), ("game.rom", &[3; 4])]);
+        let rom = load(&path, None);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rom.unwrap().data, vec![3; 4], "a single ROM image doesn't need selecting");
+    }
+    
+    #[test]
+    fn plain_and_gzip() {
+        let path = temp_path("game.a26");
+        std::fs::write(&path, [4; 4]).unwrap();
+        let rom = load(&path, Some("ignored")).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(rom.data, vec![4; 4]);
+        assert_eq!(rom.name, path.file_name().unwrap().to_string_lossy());
+        
+        // the name in the gzip header is used when there is one, else the .gz suffix is dropped in any case
+        let named = temp_path("named.gz");
+        let mut encoder = GzBuilder::new().filename("original.bin").write(File::create(&named).unwrap(), Compression::default());
+        encoder.write_all(&[5; 4]).unwrap();
+        encoder.finish().unwrap();
+        let unnamed = temp_path("game.a26.GZ");
+        let mut encoder = GzEncoder::new(File::create(&unnamed).unwrap(), Compression::default());
+        encoder.write_all(&[6; 4]).unwrap();
+        encoder.finish().unwrap();
+        
+        let named_rom = load(&named, None).unwrap();
+        let unnamed_rom = load(&unnamed, None).unwrap();
+        std::fs::remove_file(&named).unwrap();
+        std::fs::remove_file(&unnamed).unwrap();
+        assert_eq!((named_rom.name.as_str(), named_rom.data), ("original.bin", vec![5; 4]));
+        assert_eq!(unnamed_rom.name, temp_path("game.a26").file_name().unwrap().to_string_lossy());
+        assert_eq!(unnamed_rom.data, vec![6; 4]);
+    }
+}
\ No newline at end of file
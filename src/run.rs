@@ -0,0 +1,65 @@
+use crate::arch::Bus;
+use crate::arch::input::Input;
+use crate::util::InfCell;
+
+/// Color clocks in a full NTSC frame. A step never runs for more than two of these, so a ROM that
+/// never starts VSYNC can't hang the frontend.
+const FRAME_CLOCKS: usize = 228 * 262;
+
+/// Whether the frontend keeps the machine running on its own.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum RunState {
+    #[default]
+    Running,
+    /// Only advances when asked to, by one [`Step`] at a time.
+    Paused,
+}
+
+/// How far one step runs the machine.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Step {
+    /// Until the next VSYNC rising edge, which is where a new frame starts.
+    Frame,
+    /// Until the start of the next scanline.
+    Scanline,
+    /// Until the CPU finishes its current instruction, or the next one if it's between instructions.
+    Instruction,
+}
+
+/// Clocks the machine by one step. `input` is applied at the start of every new frame, so the
+/// inputs only ever change on frame boundaries, no matter how the machine is stepped. Lag frames
+/// are tracked here too (see [`Bus::start_frame`]).
+///
+/// Returns whether a new frame was started during the step.
+pub fn step(bus_cell: &InfCell<Bus>, step: Step, input: &Input) -> bool {
+    let bus = bus_cell.get_mut();
+    let mut new_frame = false;
+    let mut in_instruction = false;
+    
+    for _ in 0..(FRAME_CLOCKS * 2) {
+        let vsync = bus.tia.in_vsync();
+        bus.tia.cycle(bus_cell);
+        if !vsync && bus.tia.in_vsync() {
+            bus.start_frame();
+            input.apply(bus);
+            new_frame = true;
+        }
+        
+        let done = match step {
+            Step::Frame => new_frame,
+            Step::Scanline => bus.tia.cycles.color_clock == 0,
+            Step::Instruction => {
+                let between = bus.cpu.between_instructions();
+                let done = in_instruction && between;
+                in_instruction |= !between;
+                
+                done
+            },
+        };
+        if done {
+            break;
+        }
+    }
+    
+    new_frame
+}
\ No newline at end of file
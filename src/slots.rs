@@ -0,0 +1,122 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use crate::arch::Bus;
+
+/// First color clock of a scanline that's actually displayed, after horizontal blanking.
+const HBLANK: usize = 68;
+
+/// Numbered savestate slots (0-9), kept next to the ROM as `<rom>.<slot>.state`, each with a
+/// `<rom>.<slot>.png` thumbnail of the picture at the time it was saved.
+#[derive(Clone, Debug)]
+pub struct Slots {
+    rom_path: PathBuf,
+    pub current: u8,
+}
+impl Slots {
+    pub fn new<P: AsRef<Path>>(rom_path: P) -> Self {
+        Self {
+            rom_path: rom_path.as_ref().to_owned(),
+            current: 0,
+        }
+    }
+    
+    fn path(&self, slot: u8, extension: &str) -> PathBuf {
+        let mut name = self.rom_path.file_name().unwrap_or_default().to_owned();
+        name.push(format!(".{}.{}", slot, extension));
+        
+        self.rom_path.with_file_name(name)
+    }
+    
+    pub fn state_path(&self, slot: u8) -> PathBuf {
+        self.path(slot, "state")
+    }
+    
+    pub fn thumbnail_path(&self, slot: u8) -> PathBuf {
+        self.path(slot, "png")
+    }
+    
+    /// Saves the machine into the current slot. `ystart` and `height` select the scanlines that
+    /// go into the thumbnail.
+    pub fn save(&self, bus: &Bus, ystart: usize, height: usize) -> Result<(), String> {
+        let path = self.state_path(self.current);
+        std::fs::write(&path, bus.save_state()).map_err(|err| format!("{}: {}", path.display(), err))?;
+        
+        let path = self.thumbnail_path(self.current);
+        write_thumbnail(&path, &bus.tia.framebuffer, ystart, height).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+    
+    /// Loads the current slot into the machine.
+    pub fn load(&self, bus: &mut Bus) -> Result<(), String> {
+        load_state_file(bus, self.state_path(self.current))
+    }
+}
+
+/// Loads a savestate file into the machine.
+pub fn load_state_file<P: AsRef<Path>>(bus: &mut Bus, path: P) -> Result<(), String> {
+    let path = path.as_ref();
+    let state = std::fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    
+    bus.load_state(&state).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+/// Writes the visible part of the framebuffer (without horizontal blanking) as an RGB PNG.
+pub fn write_thumbnail(path: &Path, framebuffer: &[u32], ystart: usize, height: usize) -> Result<(), png::EncodingError> {
+    let width = 228 - HBLANK;
+    let mut data = Vec::with_capacity(width * height * 3);
+    for row in framebuffer.chunks(228).skip(ystart).take(height) {
+        for pixel in &row[HBLANK..] {
+            data.extend_from_slice(&pixel.to_be_bytes()[1..]);
+        }
+    }
+    
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn paths() {
+        let slots = Slots::new("roms/Pitfall!.a26");
+        assert_eq!(slots.state_path(3), Path::new("roms/Pitfall!.a26.3.state"));
+        assert_eq!(slots.thumbnail_path(0), Path::new("roms/Pitfall!.a26.0.png"));
+    }
+    
+    #[test]
+    fn thumbnail() {
+        // each pixel encodes its own position, with a marker in the top byte that has to be dropped
+        let framebuffer = (0..228 * 262).map(|i: u32| 0xFF000000 | (i / 228) << 8 | (i % 228)).collect::<Vec<_>>();
+        let path = std::env::temp_dir().join(format!("rustari2600-test-{}.png", std::process::id()));
+        write_thumbnail(&path, &framebuffer, 30, 192).unwrap();
+        
+        let mut reader = png::Decoder::new(File::open(&path).unwrap()).read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((info.width, info.height), (228 - HBLANK as u32, 192));
+        assert_eq!((info.color_type, info.bit_depth), (png::ColorType::Rgb, png::BitDepth::Eight));
+        assert_eq!(data[..3], [0, 30, HBLANK as u8], "first pixel isn't the first visible one");
+        assert_eq!(data[data.len() - 3..], [0, 30 + 191, 227]);
+    }
+    
+    #[test]
+    fn load_errors() {
+        let path = std::env::temp_dir().join(format!("rustari2600-test-{}.a26", std::process::id()));
+        let slots = Slots::new(&path);
+        let mut bus = Box::<Bus>::default();
+        assert!(slots.load(&mut bus).is_err(), "missing file");
+        
+        std::fs::write(slots.state_path(0), b"not a savestate").unwrap();
+        let corrupt = load_state_file(&mut bus, slots.state_path(0));
+        std::fs::write(slots.state_path(0), &bus.save_state()[..100]).unwrap();
+        let truncated = slots.load(&mut bus);
+        std::fs::remove_file(slots.state_path(0)).unwrap();
+        assert!(corrupt.is_err(), "corrupt file");
+        assert!(truncated.is_err(), "truncated file");
+    }
+}
\ No newline at end of file
@@ -0,0 +1,401 @@
+use std::collections::BTreeMap;
+use crate::arch::Bus;
+use crate::arch::input::Input;
+use crate::movie::{Frame, Movie};
+use crate::rewind::{apply_delta, encode_delta};
+use crate::run::{self, Step};
+use crate::util::InfCell;
+
+/// Most times a single frame tries to run until VSYNC. Each try is up to two frames of clocks.
+const MAX_FRAME_STEPS: usize = 30;
+/// Every this many frames, a greenzone state is kept whole, so getting a state back never takes
+/// undoing more than this many deltas.
+const KEYFRAME_INTERVAL: usize = 32;
+
+/// Greenzone capacity that suits most sessions. A whole state is about 240KB, mostly the picture,
+/// but only one frame in every [`KEYFRAME_INTERVAL`] keeps one, so this holds a few thousand
+/// frames of a game that doesn't redraw the whole screen every frame.
+pub const DEFAULT_CAPACITY: usize = 64 * 1024 * 1024;
+
+/// A state in the greenzone, either whole or as a delta against the state of the frame after it
+/// (see [`encode_delta`]).
+#[derive(Clone, Debug)]
+enum Saved {
+    Full(Vec<u8>),
+    Delta(Vec<u8>),
+}
+impl Saved {
+    fn len(&self) -> usize {
+        match self {
+            Saved::Full(state) => state.len(),
+            Saved::Delta(delta) => delta.len(),
+        }
+    }
+}
+
+/// A saved branch of a TAS: the movie as it was, and where the machine was in it.
+#[derive(Clone, Debug)]
+pub struct Branch {
+    pub movie: Movie,
+    pub frame: usize,
+    state: Vec<u8>,
+}
+
+/// Backend for editing a movie while it's being played, as a piano roll editor would.
+///
+/// The machine's state at the start of every frame that has been emulated is kept in the
+/// "greenzone", so the movie can be seeked to any frame by loading the closest state before it and
+/// emulating from there. Like in [`Rewind`](crate::rewind::Rewind), most states are stored as
+/// deltas against the next frame's, which take a small fraction of the space. Changing the input of a frame drops the states from that frame on, since
+/// they no longer follow from the movie, and the machine is brought back to where it was.
+///
+/// Frame `n` starts at the `n`th VSYNC rising edge, right after its input is applied, like when
+/// the movie is played back normally.
+pub struct TasSession {
+    /// Boxed, since the machine is too big to keep moving around on the stack.
+    bus_cell: Box<InfCell<Bus>>,
+    movie: Movie,
+    /// Number of frames that have started, so the machine is at the start of frame `frame - 1`.
+    frame: usize,
+    /// State of the machine at power-on, before the first frame.
+    power_on: Vec<u8>,
+    /// State of the machine at the start of each frame, by frame. A delta always has the state it
+    /// was made against right after it.
+    greenzone: BTreeMap<usize, Saved>,
+    /// Maximum number of bytes used by the greenzone.
+    capacity: usize,
+    size: usize,
+}
+impl TasSession {
+    /// Starts a session for a machine that was just powered on with the ROM loaded and the CPU
+    /// reset, keeping up to `capacity` bytes of states in the greenzone ([`DEFAULT_CAPACITY`] is a
+    /// good start).
+    pub fn new(mut bus: Bus, movie: Movie, capacity: usize) -> Result<Self, String> {
+        movie.start(&mut bus)?;
+        
+        Ok(Self {
+            power_on: bus.save_state(),
+            bus_cell: Box::new(InfCell::new(bus)),
+            movie,
+            frame: 0,
+            greenzone: BTreeMap::new(),
+            capacity,
+            size: 0,
+        })
+    }
+    
+    pub fn bus(&self) -> &Bus {
+        self.bus_cell.get()
+    }
+    
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+    
+    /// Number of frames that have started since power-on.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+    
+    /// Frames that have a state in the greenzone.
+    pub fn greenzone(&self) -> impl Iterator<Item = usize> + '_ {
+        self.greenzone.keys().copied()
+    }
+    
+    /// Brings the machine to the point where `frame` frames have started, emulating from the
+    /// closest state in the greenzone. Frames past the end of the movie get no input.
+    pub fn seek(&mut self, frame: usize) {
+        // start from the closest state before the target, unless the machine is already closer
+        let closest = self.greenzone.range(..frame).next_back().map(|(i, _)| *i);
+        let closest_frame = closest.map_or(0, |i| i + 1);
+        if frame < self.frame || closest_frame > self.frame {
+            let state = closest.map_or_else(|| self.power_on.clone(), |i| self.state(i));
+            self.bus_cell.get_mut().load_state(&state).unwrap();
+            self.frame = closest_frame;
+        }
+        
+        while self.frame < frame {
+            self.run_frame();
+        }
+    }
+    
+    /// Emulates the next frame.
+    pub fn advance(&mut self) {
+        self.seek(self.frame + 1);
+    }
+    
+    /// Runs until the next frame starts, applying its input and saving it to the greenzone.
+    fn run_frame(&mut self) {
+        let input = self.movie.frames.get(self.frame).map(|frame| frame.input).unwrap_or_default();
+        let bus = self.bus_cell.get_mut();
+        // frames end at VSYNC, but a ROM that never does it shouldn't hang the session
+        for _ in 0..MAX_FRAME_STEPS {
+            if run::step(&self.bus_cell, Step::Frame, &input) {
+                break;
+            }
+        }
+        
+        if let Some(previous) = self.frame.checked_sub(1).and_then(|i| self.movie.frames.get_mut(i)) {
+            previous.lag = bus.lag;
+        }
+        self.insert_state(self.frame, bus.save_state());
+        self.frame += 1;
+    }
+    
+    /// Greenzone state of `frame`, which has to be in it, undoing the deltas back from the next
+    /// whole state.
+    fn state(&self, frame: usize) -> Vec<u8> {
+        let mut deltas: Vec<&[u8]> = vec![];
+        for (_, saved) in self.greenzone.range(frame..) {
+            match saved {
+                Saved::Full(state) => {
+                    let mut state = state.clone();
+                    for delta in deltas.iter().rev() {
+                        apply_delta(&mut state, delta);
+                    }
+                    return state;
+                },
+                Saved::Delta(delta) => deltas.push(delta),
+            }
+        }
+        
+        panic!("greenzone delta for frame {} has no whole state after it", frame);
+    }
+    
+    fn put(&mut self, frame: usize, saved: Saved) {
+        self.size += saved.len();
+        if let Some(old) = self.greenzone.insert(frame, saved) {
+            self.size -= old.len();
+        }
+    }
+    
+    /// Makes the state of the frame before `frame` whole if it's a delta, so the state of `frame`
+    /// can be replaced or dropped.
+    fn detach(&mut self, frame: usize) {
+        if let Some(previous) = frame.checked_sub(1) {
+            if let Some(Saved::Delta(_)) = self.greenzone.get(&previous) {
+                let state = self.state(previous);
+                self.put(previous, Saved::Full(state));
+            }
+        }
+    }
+    
+    fn insert_state(&mut self, frame: usize, state: Vec<u8>) {
+        self.detach(frame);
+        // the previous state only needs to be kept as a delta against this one
+        if let Some(previous) = frame.checked_sub(1).filter(|previous| !previous.is_multiple_of(KEYFRAME_INTERVAL)) {
+            if let Some(Saved::Full(previous_state)) = self.greenzone.get(&previous) {
+                let delta = encode_delta(previous_state, &state);
+                self.put(previous, Saved::Delta(delta));
+            }
+        }
+        self.put(frame, Saved::Full(state));
+        
+        // drop the states furthest from where the machine is, which are the least likely to be needed
+        while self.size > self.capacity {
+            let first = *self.greenzone.keys().next().unwrap();
+            let last = *self.greenzone.keys().next_back().unwrap();
+            let furthest = if self.frame.abs_diff(first) >= self.frame.abs_diff(last) { first } else { last };
+            self.detach(furthest);
+            self.size -= self.greenzone.remove(&furthest).unwrap().len();
+        }
+    }
+    
+    /// Drops the states of `frame` and every frame after it from the greenzone.
+    fn truncate(&mut self, frame: usize) {
+        self.detach(frame);
+        for (_, saved) in self.greenzone.split_off(&frame) {
+            self.size -= saved.len();
+        }
+    }
+    
+    /// Drops the greenzone from `frame` on, and the hashes in the movie that can't be trusted
+    /// anymore. Then brings the machine back to where it was.
+    fn invalidate(&mut self, frame: usize) {
+        self.truncate(frame);
+        for frame in self.movie.frames.iter_mut().skip(frame) {
+            frame.hash = None;
+        }
+        
+        if frame < self.frame {
+            let current = self.frame;
+            self.seek(frame);
+            self.seek(current);
+        }
+    }
+    
+    /// Changes the input of a frame, extending the movie if needed.
+    pub fn set_input(&mut self, frame: usize, input: Input) {
+        if frame >= self.movie.frames.len() {
+            self.movie.frames.resize(frame + 1, Frame::default());
+        }
+        if self.movie.frames[frame].input != input {
+            self.movie.frames[frame].input = input;
+            self.invalidate(frame);
+        }
+    }
+    
+    /// Inserts a frame without input before `frame`, moving the rest of the movie one frame later.
+    pub fn insert_frame(&mut self, frame: usize) {
+        self.movie.frames.insert(frame.min(self.movie.frames.len()), Frame::default());
+        self.invalidate(frame);
+    }
+    
+    /// Removes a frame, moving the rest of the movie one frame earlier.
+    pub fn remove_frame(&mut self, frame: usize) {
+        if frame < self.movie.frames.len() {
+            self.movie.frames.remove(frame);
+            self.invalidate(frame);
+        }
+    }
+    
+    /// Saves the movie and the machine's current state as a branch.
+    pub fn save_branch(&self) -> Branch {
+        Branch {
+            movie: self.movie.clone(),
+            frame: self.frame,
+            state: self.bus().save_state(),
+        }
+    }
+    
+    /// Switches to a branch. The greenzone is kept up to the first frame where the branch's input
+    /// differs from the current movie.
+    pub fn load_branch(&mut self, branch: &Branch) {
+        let differs = self.movie.frames.iter().zip(&branch.movie.frames)
+            .position(|(a, b)| a.input != b.input)
+            .unwrap_or_else(|| self.movie.frames.len().min(branch.movie.frames.len()));
+        
+        self.movie = branch.movie.clone();
+        self.truncate(differs);
+        
+        self.bus_cell.get_mut().load_state(&branch.state).unwrap();
+        self.frame = branch.frame;
+        if let Some(frame) = branch.frame.checked_sub(1) {
+            self.insert_state(frame, branch.state.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::input::Joystick;
+    use crate::arch::tests::machine;
+    
+    /// Session for a game that XORs everything it reads from the controllers into RAM, so that
+    /// the machine's state depends on the input of every frame so far.
+    fn session(frames: usize) -> TasSession {
+        // loop: LDA SWCHA; EOR $81; STA $81; LDA INPT4; EOR $82; STA $82; STA WSYNC;
+        //       VSYNC for two scanlines; JMP loop
+        let bus_cell = machine(&[0xAD, 0x80, 0x02, 0x45, 0x81, 0x85, 0x81, 0xA5, 0x0C, 0x45, 0x82, 0x85, 0x82, 0x85, 0x02,
+            0xA9, 0x02, 0x85, 0x00, 0x85, 0x02, 0x85, 0x02, 0xA9, 0x00, 0x85, 0x00, 0x4C, 0x00, 0xF0]);
+        let bus = bus_cell.get().clone();
+        let mut movie = Movie::new(&bus);
+        movie.frames = (0..frames).map(|i| Frame { input: input(i), ..Frame::default() }).collect();
+        
+        TasSession::new(bus, movie, usize::MAX).unwrap()
+    }
+    
+    fn input(frame: usize) -> Input {
+        Input { joysticks: [Joystick::from_bits_truncate(frame as u8), Joystick::empty()], ..Input::default() }
+    }
+    
+    /// Hash of the machine after replaying `movie` from power-on up to `frame`.
+    fn replay(movie: &Movie, frame: usize) -> u64 {
+        let mut fresh = session(0);
+        fresh.movie = movie.clone();
+        fresh.seek(frame);
+        
+        fresh.bus().state_hash()
+    }
+    
+    #[test]
+    fn edit_matches_replay() {
+        let mut session = session(30);
+        session.seek(30);
+        let unedited = session.bus().state_hash();
+        assert_eq!(unedited, replay(session.movie(), 30));
+        
+        // the machine is brought back to the same frame, replayed from the greenzone
+        session.set_input(10, Input { joysticks: [Joystick::FIRE, Joystick::UP], ..Input::default() });
+        assert_eq!(session.frame(), 30);
+        let edited = session.bus().state_hash();
+        assert_ne!(edited, unedited);
+        assert_eq!(edited, replay(session.movie(), 30));
+        
+        session.insert_frame(3);
+        session.remove_frame(20);
+        assert_eq!(session.bus().state_hash(), replay(session.movie(), 30));
+        
+        session.seek(12);
+        assert_eq!(session.bus().state_hash(), replay(session.movie(), 12));
+    }
+    
+    #[test]
+    fn invalidate() {
+        let mut session = session(30);
+        session.seek(30);
+        assert!(session.greenzone().eq(0..30));
+        session.seek(5);
+        session.movie.frames[15].hash = Some(1);
+        
+        session.set_input(10, Input::default());
+        assert!(session.greenzone().eq(0..10), "greenzone still has states after the edited frame");
+        assert_eq!(session.movie().frames[15].hash, None);
+        assert_eq!(session.frame(), 5);
+        
+        // setting the input a frame already has doesn't invalidate anything
+        session.seek(30);
+        session.set_input(20, input(20));
+        assert!(session.greenzone().eq(0..30));
+    }
+    
+    #[test]
+    fn greenzone_deltas() {
+        let mut session = session(100);
+        session.seek(100);
+        let whole = session.bus().save_state().len();
+        assert!(session.size < 100 * whole / 10, "{} bytes for 100 states of {}", session.size, whole);
+        
+        let mut fresh = self::session(100);
+        for frame in [0, 1, 31, 32, 33, 64, 98, 99] {
+            fresh.seek(frame + 1);
+            assert!(session.state(frame) == fresh.bus().save_state(), "state of frame {} differs", frame);
+        }
+        
+        // with room for only a few states, the ones kept are next to the machine and still decode
+        session.capacity = whole * 3;
+        session.set_input(90, Input::default());
+        assert!(session.size <= session.capacity);
+        let first = session.greenzone().next().unwrap();
+        assert!(first > 0 && session.greenzone().eq(first..100));
+        assert!(100 - first > 3, "no more states kept than whole ones would fit");
+        for frame in session.greenzone().collect::<Vec<_>>() {
+            session.state(frame);
+        }
+        session.seek(95);
+        assert_eq!(session.bus().state_hash(), replay(session.movie(), 95));
+    }
+    
+    #[test]
+    fn branches() {
+        let mut session = session(30);
+        session.seek(20);
+        let hash = session.bus().state_hash();
+        let branch = session.save_branch();
+        
+        session.set_input(5, Input { reset: true, ..Input::default() });
+        session.seek(25);
+        assert_ne!(session.movie(), &branch.movie);
+        
+        session.load_branch(&branch);
+        assert_eq!(session.frame(), 20);
+        assert_eq!(session.movie(), &branch.movie);
+        assert_eq!(session.bus().state_hash(), hash);
+        // the greenzone is only kept before the first frame where the input differed
+        assert!(session.greenzone().filter(|frame| *frame >= 5).eq([19]));
+        
+        session.seek(30);
+        assert_eq!(session.bus().state_hash(), replay(&branch.movie, 30));
+    }
+}
\ No newline at end of file
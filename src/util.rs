@@ -1,4 +1,5 @@
 use std::cell::UnsafeCell;
+use crate::arch::state::impl_savestate;
 
 /// Infinite access unsafe cell. Multiple mutable references of this data can exist
 /// across threads. No locking or any kind of safety checks are performed.
@@ -34,4 +35,40 @@ impl<T> InfCell<T> {
 }
 
 unsafe impl<T> Send for InfCell<T> {}
-//unsafe impl<T> Sync for InfCell<T> {}
\ No newline at end of file
+//unsafe impl<T> Sync for InfCell<T> {}
+
+/// 64-bit FNV-1a hash. Like [`Rng`], not suitable for anything security related, but simple and fast.
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xCBF29CE484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001B3))
+}
+
+/// Small splitmix64 pseudo random number generator. Not suitable for anything security related,
+/// but it is fast and always produces the same sequence for the same seed.
+#[derive(Copy, Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+impl_savestate!(Rng { state });
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+    
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        
+        z ^ (z >> 31)
+    }
+    
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+}
\ No newline at end of file